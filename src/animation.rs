@@ -1,6 +1,7 @@
-use ggez::graphics;
 use player::Facing;
+use textures::Texture;
 
+#[derive(Debug, PartialEq)]
 pub enum PlayerAnimationStatus {
     Walking,
     Standing,
@@ -10,15 +11,15 @@ pub enum PlayerAnimationStatus {
 
 #[derive(Debug)]
 pub struct LeftRightImage {
-    pub left: graphics::Image,
-    pub right: graphics::Image
+    pub left: Texture,
+    pub right: Texture,
 }
 
 impl LeftRightImage {
-    pub fn face(&self, f: &Facing) -> &graphics::Image {
+    pub fn face(&self, f: &Facing) -> Texture {
         match f {
-            &Facing::Left => &self.left,
-            &Facing::Right => &self.right,
+            &Facing::Left => self.left,
+            &Facing::Right => self.right,
         }
     }
 }
@@ -29,7 +30,6 @@ pub struct PlayerAnimation {
     pub walk: [LeftRightImage; 2],
     pub jump: LeftRightImage,
     pub fall: LeftRightImage,
-    pub time: f64,
 }
 
 impl PlayerAnimation {
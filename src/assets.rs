@@ -1,45 +1,49 @@
 use ggez::graphics;
 use ggez::audio;
-use ggez::{Context, GameResult};
+use ggez::{Context, GameError, GameResult};
+use animation::PlayerAnimation;
+use bonus::BonusDefinition;
+use particles::ParticleDefinition;
+use helpers;
+use player::Player;
+use textures::{Texture, TextureCache};
+use texts::TextCache;
 
 // Assets
 pub struct Assets {
     pub font: graphics::Font,
     pub font_small: graphics::Font,
-    pub blood: graphics::Image,
-    pub bg: graphics::Image,
     pub shadow: graphics::Image,
+    pub textures: TextureCache,
+    pub player_animations: Vec<PlayerAnimation>,
+    // One per bonus definition, several bonuses can share an image
+    pub bonus_images: Vec<Texture>,
+    // One per particle definition
+    pub particle_images: Vec<Texture>,
+    pub texts: TextCache,
+    pub factory: graphics::Image,
+    pub factory_alt: graphics::Image,
     pub title: graphics::Text,
     pub authors: graphics::Text,
     pub single: graphics::Text,
-    pub instructions_p1: Vec<graphics::Text>,
-    pub instructions_p2: Vec<graphics::Text>,
     pub credits: Vec<graphics::Text>,
+    pub player_counts: Vec<graphics::Text>,
     pub jump: Vec<audio::Source>,
     pub death: audio::Source,
     pub take_bonus: audio::Source,
 }
 
 impl Assets {
-    pub fn new(ctx: &mut Context) -> GameResult<Assets> {
+    pub fn new(
+        ctx: &mut Context,
+        bonus_definitions: &[BonusDefinition],
+        particle_definitions: &[ParticleDefinition],
+    ) -> GameResult<Assets> {
         let font = graphics::Font::new(ctx, "/TravelingTypewriter.ttf", 18)?;
         let font_small = graphics::Font::new(ctx, "/TravelingTypewriter.ttf", 12)?;
-        let blood = graphics::Image::new(ctx, "/blood.png")?;
         let title = graphics::Text::new(ctx, "LLAMASSACRE", &font)?;
-        let authors = graphics::Text::new(ctx, "press SPACE to start, C for credits", &font_small)?;
-        let single = graphics::Text::new(ctx, "single player? press ESC to quit", &font_small)?;
-        let instructions_p1 = vec![
-            graphics::Text::new(ctx, "Player 1:", &font_small)?,
-            graphics::Text::new(ctx, "E: jump", &font_small)?,
-            graphics::Text::new(ctx, "S: left", &font_small)?,
-            graphics::Text::new(ctx, "F: right", &font_small)?,
-        ];
-        let instructions_p2 = vec![
-            graphics::Text::new(ctx, "Player 2:", &font_small)?,
-            graphics::Text::new(ctx, "up: jump", &font_small)?,
-            graphics::Text::new(ctx, "left: left", &font_small)?,
-            graphics::Text::new(ctx, "right: right", &font_small)?,
-        ];
+        let authors = graphics::Text::new(ctx, "press SPACE (or START) to start, K for controls, C for credits", &font_small)?;
+        let single = graphics::Text::new(ctx, "F1-F8: switch a player between human and bot", &font_small)?;
         let credits = vec![
             graphics::Text::new(ctx, "Artwork & design: iorekb", &font_small)?,
             graphics::Text::new(ctx, "Prog, artwork & design: rap2h", &font_small)?,
@@ -47,29 +51,61 @@ impl Assets {
             graphics::Text::new(ctx, "Font: TravelingTypewriter", &font_small)?,
             graphics::Text::new(ctx, "Sounds plundered on freesound.org", &font_small)?,
             graphics::Text::new(ctx, "Artwork highly inspired by various artists", &font_small)?,
+            graphics::Text::new(ctx, "press any key to go back", &font_small)?,
         ];
-        let jump = audio::Source::new(ctx, "/jump.ogg")?;
-        let jump2 = audio::Source::new(ctx, "/jump.ogg")?;
+        let mut player_counts = vec![];
+        for count in Player::MIN_COUNT..Player::MAX_COUNT + 1 {
+            let text = format!("{} players (press {}-{})", count, Player::MIN_COUNT, Player::MAX_COUNT);
+            player_counts.push(graphics::Text::new(ctx, &text, &font_small)?);
+        }
+        // One source per player so that jumps can overlap. P1 jumps with
+        // /jump1.ogg if there is one, with the shared /jump.ogg otherwise.
+        let mut jump = vec![];
+        for i in 0..Player::MAX_COUNT {
+            let own = format!("/jump{}.ogg", i + 1);
+            let path = if ctx.filesystem.exists(&own) { own.as_str() } else { "/jump.ogg" };
+            jump.push(audio::Source::new(ctx, path)?);
+        }
         let take_bonus = audio::Source::new(ctx, "/bonus.wav")?;
         let death = audio::Source::new(ctx, "/death.ogg")?;
-        let mut bg = graphics::Image::new(ctx, "/bg10.png")?;
         let shadow = graphics::Image::new(ctx, "/shadow2.png")?;
-        bg.set_filter(graphics::FilterMode::Nearest);
+        let mut textures = TextureCache::new();
+        let player_animations = vec![
+            helpers::player1_animation(ctx, &mut textures)?,
+            helpers::player2_animation(ctx, &mut textures)?,
+        ];
+        let mut bonus_images = vec![];
+        for definition in bonus_definitions {
+            let image = helpers::sprite(ctx, &mut textures, &definition.sprite)
+                .map_err(|e| GameError::ResourceLoadError(format!("bonus {}: {}", definition.name, e)))?;
+            bonus_images.push(image);
+        }
+        let mut particle_images = vec![];
+        for definition in particle_definitions {
+            particle_images.push(helpers::sprite(ctx, &mut textures, &definition.sprite)?);
+        }
+        let texts = TextCache::new(ctx, &font, &font_small)?;
+        let factory = graphics::Image::new(ctx, "/divin2.png")?;
+        let factory_alt = graphics::Image::new(ctx, "/divin1.png")?;
         let s = Assets {
             font: font,
             font_small: font_small,
-            blood: blood,
-            bg: bg,
             title: title,
-            jump: vec![jump, jump2],
+            jump: jump,
             death: death,
             take_bonus: take_bonus,
             authors: authors,
-            instructions_p1: instructions_p1,
-            instructions_p2: instructions_p2,
             shadow: shadow,
+            textures: textures,
+            player_animations: player_animations,
+            bonus_images: bonus_images,
+            particle_images: particle_images,
+            texts: texts,
+            factory: factory,
+            factory_alt: factory_alt,
             single: single,
-            credits: credits
+            credits: credits,
+            player_counts: player_counts,
         };
         Ok(s)
     }
@@ -1,101 +1,257 @@
+use std::io::Read;
 use Vector2;
 use graphics;
-use std::time::Duration;
-use ggez::{Context, GameResult};
-use rand::{random, thread_rng, Rng};
-use ggez::timer;
+use ggez::{Context, GameError, GameResult};
+use toml;
+use rand::Rng;
+use rng::GameRng;
 use helpers;
+use assets::Assets;
 use display::Screen;
 use player::Player;
+use collision::{Aabb, Body};
 
-pub enum BonusType {
-    GiveOnePoint,
-    GiveFivePoint,
-    Velocity2,
-    Freeze,
-    Reverse,
-    Enlarge,
+// What a bonus does, loaded from resources/bonuses.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BonusDefinition {
+    pub name: String,
+    pub sprite: String,
+    pub text: String,
+    // Relative chance of being picked when the factory drops a bonus
+    pub weight: u32,
+    // Points given, or taken if negative
+    #[serde(default)]
+    pub score: i32,
+    // Seconds the mutation lasts, no mutation if 0
+    #[serde(default)]
+    pub duration: f64,
+    #[serde(default = "one")]
+    pub size_factor: f64,
+    #[serde(default = "ones")]
+    pub velocity_factor: [f64; 2],
+    // Absorbs one stomp
+    #[serde(default)]
+    pub shield: bool,
+    // Jumps allowed in mid-air
+    #[serde(default)]
+    pub extra_jumps: u32,
+    // Can neither stomp nor be stomped
+    #[serde(default)]
+    pub ghost: bool,
+    // Swaps places with a random opponent, right away
+    #[serde(default)]
+    pub teleport: bool,
+    // What happens when the mutation is taken while already active
+    #[serde(default)]
+    pub stacking: Stacking,
+    // Only for the stack policy
+    #[serde(default = "one_stack")]
+    pub max_stacks: u32,
+    // Mutations replacing each other, defaults to the name
+    #[serde(default)]
+    pub group: Option<String>,
+    // Color of the mutated llama and of its timer bar
+    #[serde(default)]
+    pub tint: Option<[u8; 3]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stacking {
+    // One at a time, taking it again restarts the timer
+    Refresh,
+    // Several at once up to max_stacks, effects multiply
+    Stack,
+    // Ends the other mutations of the group first
+    Replace,
+}
+
+impl Default for Stacking {
+    fn default() -> Stacking {
+        Stacking::Refresh
+    }
+}
+
+#[derive(Deserialize)]
+struct BonusFile {
+    bonus: Vec<BonusDefinition>,
 }
 
+fn one() -> f64 {
+    1.
+}
+
+fn ones() -> [f64; 2] {
+    [1., 1.]
+}
+
+fn one_stack() -> u32 {
+    1
+}
+
+impl BonusDefinition {
+    const FILE: &'static str = "/bonuses.toml";
+
+    pub fn load_all(ctx: &mut Context) -> GameResult<Vec<BonusDefinition>> {
+        let mut content = String::new();
+        ctx.filesystem.open(Self::FILE)?.read_to_string(&mut content)?;
+        let error = |e: String| GameError::ResourceLoadError(format!("{}: {}", Self::FILE, e));
+        let definitions = Self::parse(&content).map_err(&error)?;
+        // Caught here rather than halfway through loading the assets
+        for (i, definition) in definitions.iter().enumerate() {
+            if !ctx.filesystem.exists(&definition.sprite) {
+                let message = format!("sprite {} not found", definition.sprite);
+                return Err(error(format!("bonus {} ({}): {}", i + 1, definition.name, message)));
+            }
+        }
+        Ok(definitions)
+    }
+
+    pub fn parse(content: &str) -> Result<Vec<BonusDefinition>, String> {
+        let file: BonusFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        if file.bonus.is_empty() {
+            return Err("no [[bonus]] defined".to_string());
+        }
+        for (i, definition) in file.bonus.iter().enumerate() {
+            definition
+                .validate()
+                .map_err(|e| format!("bonus {} ({}): {}", i + 1, definition.name, e))?;
+            if file.bonus[..i].iter().any(|d| d.name == definition.name) {
+                return Err(format!("bonus {} ({}): name already used", i + 1, definition.name));
+            }
+        }
+        if file.bonus.iter().all(|d| d.weight == 0) {
+            return Err("every weight is 0, no bonus would ever spawn".to_string());
+        }
+        Ok(file.bonus)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("name is empty".to_string());
+        }
+        if !self.sprite.starts_with('/') {
+            return Err(format!("sprite {} must start with /", self.sprite));
+        }
+        if self.duration < 0. {
+            return Err(format!("negative duration {}", self.duration));
+        }
+        if self.size_factor <= 0. {
+            return Err(format!("size_factor must be positive, got {}", self.size_factor));
+        }
+        if self.duration == 0. && (self.shield || self.ghost || self.extra_jumps > 0) {
+            return Err("shield, ghost and extra_jumps need a duration".to_string());
+        }
+        if self.duration > 0. && self.tint.is_none() {
+            return Err("mutations need a tint".to_string());
+        }
+        if self.max_stacks == 0 {
+            return Err("max_stacks must be at least 1".to_string());
+        }
+        if self.max_stacks > 1 && self.stacking != Stacking::Stack {
+            return Err("max_stacks is only used with stacking = \"stack\"".to_string());
+        }
+        if self.score == 0 && self.mutation().is_none() && !self.teleport {
+            return Err("no score, no mutation and no teleport, the bonus does nothing".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn mutation(&self) -> Option<Mutation> {
+        if self.duration == 0. {
+            return None;
+        }
+        let tint = self.tint.unwrap_or([255, 255, 255]);
+        Some(Mutation {
+            name: self.name.clone(),
+            group: self.group.clone().unwrap_or(self.name.clone()),
+            stacking: self.stacking,
+            max_stacks: self.max_stacks,
+            duration: self.duration,
+            total: self.duration,
+            tint: (tint[0], tint[1], tint[2]),
+            size_factor: self.size_factor,
+            velocity_factor: Vector2::new(self.velocity_factor[0], self.velocity_factor[1]),
+            shield: self.shield,
+            extra_jumps: self.extra_jumps,
+            ghost: self.ghost,
+        })
+    }
+
+    pub fn apply(&self, p: &mut Player) -> Option<Mutation> {
+        p.score = (p.score as i32 + self.score).max(0) as u32;
+        self.mutation()
+    }
+}
+
+#[derive(Clone)]
 pub struct Bonus {
-    pub tag: BonusType,
+    // Index of the definition
+    pub tag: usize,
     pub position: Vector2<f64>,
+    pub previous_position: Vector2<f64>,
     pub size: Vector2<f64>,
     pub cbox_size: Vector2<f64>,
     pub velocity: Vector2<f64>,
     pub rotation: f32,
-    pub image: graphics::Image,
     pub has_collision: bool,
-    pub description: String,
 }
 
 impl Bonus {
-    fn random(ctx: &mut Context, position: Option<Vector2<f64>>) -> GameResult<Self> {
-        let tag = Self::random_type();
-        let image = Self::image_by_tag(ctx, &tag)?;
-        let text = Self::text_by_tag(&tag);
-        let b = Bonus {
+    // Distance from the position to the bottom of the bonus
+    pub const FEET: f64 = 0.04;
+
+    fn random(rng: &mut GameRng, position: Option<Vector2<f64>>, definitions: &[BonusDefinition]) -> Self {
+        let tag = Self::random_type(rng, definitions);
+        let position = match position {
+            Some(p) => p,
+            None => Self::random_position(rng),
+        };
+        Bonus {
             tag: tag,
-            position: position.unwrap_or(Self::random_position()),
+            position: position,
+            previous_position: position,
             size: Vector2::new(0.05, 0.05),
             cbox_size: Vector2::new(0.045, 0.065),
-            velocity: Self::random_velocity(),
-            image: image,
+            velocity: Self::random_velocity(rng),
             rotation: 0.,
             has_collision: false,
-            description: text,
-        };
-        Ok(b)
-    }
-
-    fn random_type() -> BonusType {
-        match thread_rng().gen_range(0, 10) {
-            0 | 1 | 9 => BonusType::GiveOnePoint,
-            2 => BonusType::GiveFivePoint,
-            3 | 4 | 5 => BonusType::Velocity2,
-            6 => BonusType::Freeze,
-            7 => BonusType::Enlarge,
-            8 => BonusType::Reverse,
-            _ => unreachable!(),
         }
     }
 
-    fn random_position() -> Vector2<f64> {
-        Vector2::new((random::<f64>() - 0.5) / 2., 0.5)
+    fn random_type(rng: &mut GameRng, definitions: &[BonusDefinition]) -> usize {
+        let total: u32 = definitions.iter().map(|d| d.weight).sum();
+        let mut pick = rng.gen_range(0, total);
+        for (i, definition) in definitions.iter().enumerate() {
+            if pick < definition.weight {
+                return i;
+            }
+            pick -= definition.weight;
+        }
+        unreachable!()
     }
 
-    fn random_velocity() -> Vector2<f64> {
-        Vector2::new((random::<f64>() - 0.5) / 50., 0.01)
+    fn random_position(rng: &mut GameRng) -> Vector2<f64> {
+        Vector2::new((rng.gen::<f64>() - 0.5) / 2., 0.5)
     }
 
-    fn image_by_tag(ctx: &mut Context, b: &BonusType) -> GameResult<graphics::Image> {
-        let asset = match b {
-            &BonusType::GiveOnePoint => "/bonus_1.png",
-            &BonusType::GiveFivePoint => "/bonus_0.png",
-            &BonusType::Velocity2 => "/bonus_2.png",
-            &BonusType::Freeze | &BonusType::Enlarge | &BonusType::Reverse => "/bonus_3.png",
-        };
-        let mut a = graphics::Image::new(ctx, asset)?;
-        a.set_filter(graphics::FilterMode::Nearest);
-        Ok(a)
+    fn random_velocity(rng: &mut GameRng) -> Vector2<f64> {
+        Vector2::new((rng.gen::<f64>() - 0.5) / 50., 0.01)
     }
 
-
-    fn text_by_tag(b: &BonusType) -> String {
-        match b {
-            &BonusType::GiveOnePoint => "score +1",
-            &BonusType::GiveFivePoint => "score +5",
-            &BonusType::Velocity2 => "speed x2",
-            &BonusType::Freeze => "freeze",
-            &BonusType::Enlarge => "size x2",
-            &BonusType::Reverse => "reverse",
-        }.to_string()
+    pub fn body(&self) -> Body {
+        Body {
+            previous: Aabb::new(self.previous_position, self.cbox_size),
+            current: Aabb::new(self.position, self.cbox_size),
+            velocity: self.velocity,
+        }
     }
 
-    pub fn draw(&mut self, ctx: &mut Context, screen: &Screen) -> GameResult<()> {
+    pub fn draw(&self, ctx: &mut Context, screen: &Screen, assets: &Assets, alpha: f64) -> GameResult<()> {
         let size = self.size;
-        let position = self.position;
-        let image = &self.image;
+        let position = helpers::interpolate(self.previous_position, self.position, alpha);
+        let image = assets.textures.get(assets.bonus_images[self.tag]);
         let dest = helpers::point_from_position(position, screen);
         let scale = helpers::scale(size, screen, image);
         let draw_param = graphics::DrawParam {
@@ -107,57 +263,21 @@ impl Bonus {
         graphics::draw_ex(ctx, image, draw_param)?;
         Ok(())
     }
-
-    pub fn apply(&self, p: &mut Player) -> Option<Mutation> {
-        match self.tag {
-            BonusType::GiveOnePoint => {
-                p.score += 1;
-                None
-            }
-            BonusType::GiveFivePoint => {
-                p.score += 5;
-                None
-            }
-            BonusType::Velocity2 => Some(Mutation {
-                duration: 7.5,
-                size_factor: 1.,
-                velocity_factor: Vector2::new(2., 1.5),
-                active: true,
-            }),
-            BonusType::Freeze => Some(Mutation {
-                duration: 2.,
-                size_factor: 1.,
-                velocity_factor: Vector2::new(0., 0.),
-                active: true,
-            }),
-            BonusType::Enlarge => Some(Mutation {
-                duration: 5.,
-                size_factor: 1.5,
-                velocity_factor: Vector2::new(1., 1.),
-                active: true,
-            }),
-            BonusType::Reverse => Some(Mutation {
-                duration: 7.5,
-                size_factor: 1.,
-                velocity_factor: Vector2::new(-1., 1.),
-                active: true,
-            }),
-        }
-    }
 }
 
+#[derive(Clone)]
 pub struct BonusText {
-    pub text: graphics::Text,
+    pub tag: usize,
     pub position: Vector2<f64>,
     pub cooldown: f64,
 }
 
+#[derive(Clone)]
 pub struct Factory {
     pub cooldown: f64,
-    pub image: graphics::Image,
-    pub alt_image: graphics::Image,
     pub alt_image_cooldown: f64,
     pub position: Vector2<f64>,
+    pub previous_position: Vector2<f64>,
     pub velocity: Vector2<f64>,
     pub size: Vector2<f64>,
     pub rotation: f32,
@@ -165,49 +285,46 @@ pub struct Factory {
 }
 
 impl Factory {
-    pub fn new(ctx: &mut Context) -> GameResult<Self> {
-        let image = graphics::Image::new(ctx, "/divin2.png")?;
-        let alt_image = graphics::Image::new(ctx, "/divin1.png")?;
-        Ok(Self {
+    pub fn new() -> Self {
+        Self {
             cooldown: 20.,
-            image: image,
             alt_image_cooldown: 0.,
-            alt_image: alt_image,
             position: Vector2::new(0., 1.1),
+            previous_position: Vector2::new(0., 1.1),
             size: Vector2::new(0.1, 0.1),
             velocity: Vector2::new(0.15, 0.0),
             rotation: 0.,
             rotation_velocity: -1.,
-        })
+        }
     }
 
-    pub fn spawn(&mut self, ctx: &mut Context, dt: Duration) -> GameResult<Option<Vec<Bonus>>> {
-        self.cooldown -= timer::duration_to_f64(dt);
-        self.alt_image_cooldown -= timer::duration_to_f64(dt);
+    pub fn spawn(&mut self, rng: &mut GameRng, seconds: f64, definitions: &[BonusDefinition]) -> Option<Vec<Bonus>> {
+        self.cooldown -= seconds;
+        self.alt_image_cooldown -= seconds;
         if self.cooldown < 0. && self.position.x < 0.4 && self.position.x > -0.4 {
             self.alt_image_cooldown = 1.;
-            self.cooldown = Self::cooldown();
-            if random::<f64>() < 0.2 {
-                let bonus_count = thread_rng().gen_range(3, 7);
+            self.cooldown = Self::cooldown(rng);
+            if rng.gen::<f64>() < 0.2 {
+                let bonus_count = rng.gen_range(3, 7);
                 let mut r = vec![];
                 for _ in 0..bonus_count {
-                    r.push(Bonus::random(ctx, Some(self.position))?);
+                    r.push(Bonus::random(rng, Some(self.position), definitions));
                 }
-                return Ok(Some(r));
+                return Some(r);
             } else {
-                return Ok(Some(vec![Bonus::random(ctx, Some(self.position))?]));
+                return Some(vec![Bonus::random(rng, Some(self.position), definitions)]);
             }
         }
-        Ok(None)
+        None
     }
 
-    pub fn draw(&mut self, ctx: &mut Context, screen: &Screen) -> GameResult<()> {
+    pub fn draw(&self, ctx: &mut Context, screen: &Screen, assets: &Assets, alpha: f64) -> GameResult<()> {
         let size = screen.size_to_pixel(Vector2::new(0.1625, 0.34));
-        let position = self.position;
+        let position = helpers::interpolate(self.previous_position, self.position, alpha);
         let image = if self.alt_image_cooldown <= 0. {
-            &self.image
+            &assets.factory
         } else {
-            &self.alt_image
+            &assets.factory_alt
         };
 
         let dest = helpers::point_from_position(position, screen);
@@ -224,28 +341,29 @@ impl Factory {
         Ok(())
     }
 
-    fn cooldown() -> f64 {
-        thread_rng().gen_range(2., 20.)
+    fn cooldown(rng: &mut GameRng) -> f64 {
+        rng.gen_range(2., 20.)
     }
 
-    pub fn update(&mut self, seconds: f64) {
+    pub fn update(&mut self, rng: &mut GameRng, seconds: f64) {
+        self.previous_position = self.position;
         if self.position.y > 0.3 {
             self.position.y -= seconds / 50.;
         } else {
             self.position.y = 0.3;
             self.position += self.velocity * seconds;
             if self.position.x > 1.5 || self.position.x < -1.5 {
-                self.velocity.x *= thread_rng().gen_range(-1.15, -0.95);
+                self.velocity.x *= rng.gen_range(-1.15, -0.95);
                 if self.velocity.x.abs() > 0.4 {
                     self.velocity.x /= 2.
                 }
                 self.rotation = 0.;
-                match thread_rng().gen_range(0, 3) {
+                match rng.gen_range(0, 3) {
                     0 => {
                         self.rotation_velocity = 0.;
                     }
                     _ => {
-                        self.rotation_velocity = thread_rng().gen_range(-1.5, -0.5);
+                        self.rotation_velocity = rng.gen_range(-1.5, -0.5);
                     }
                 };
             }
@@ -255,10 +373,27 @@ impl Factory {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mutation {
+    // Name of the bonus that gave it
+    pub name: String,
+    pub group: String,
+    pub stacking: Stacking,
+    pub max_stacks: u32,
+    // Seconds left, out of total
     pub duration: f64,
+    pub total: f64,
+    pub tint: (u8, u8, u8),
     pub size_factor: f64,
     pub velocity_factor: Vector2<f64>,
-    pub active: bool,
+    pub shield: bool,
+    pub extra_jumps: u32,
+    pub ghost: bool,
+}
+
+impl Mutation {
+    // Timer bar length, from 1 down to 0
+    pub fn remaining(&self) -> f64 {
+        (self.duration / self.total).max(0.).min(1.)
+    }
 }
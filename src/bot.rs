@@ -0,0 +1,141 @@
+use cgmath::Vector2;
+use bonus::BonusDefinition;
+use input::Input;
+use player::Player;
+use world::World;
+use helpers::wrapped_dx;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+        }
+    }
+
+    // Ticks between two decisions
+    fn reaction(&self) -> u32 {
+        match *self {
+            Difficulty::Easy => 20,
+            Difficulty::Normal => 10,
+            Difficulty::Hard => 3,
+        }
+    }
+}
+
+// Computer controlled llama, it produces the same inputs as a human would
+pub struct Bot {
+    pub difficulty: Difficulty,
+    cooldown: u32,
+    input: Input,
+}
+
+impl Bot {
+    // Horizontal distance under which a llama is considered above another one
+    const DANGER_ZONE: f64 = 0.08;
+
+    pub fn new(difficulty: Difficulty) -> Bot {
+        Bot {
+            difficulty: difficulty,
+            cooldown: 0,
+            input: Input::default(),
+        }
+    }
+
+    pub fn think(&mut self, world: &World, index: usize) -> Input {
+        if self.cooldown > 0 {
+            self.cooldown -= 1;
+            return self.input;
+        }
+        self.cooldown = self.difficulty.reaction();
+
+        let me = &world.players[index];
+        let mut axis = Vector2::new(0., 0.);
+
+        if let Some(dx) = self.threat(world, me) {
+            // Run away from whoever is about to land on us
+            axis.x = if dx > 0. { -1. } else { 1. };
+        } else if let Some(dx) = self.wanted_bonus(world, me) {
+            axis.x = dx.signum();
+        } else if let Some(opponent) = nearest_opponent(world, me) {
+            let dx = wrapped_dx(me.position.x, opponent.position.x);
+            if dx.abs() > 0.01 {
+                axis.x = dx.signum();
+            }
+            // Jump early enough to come down on the opponent
+            if dx.abs() < 0.2 && me.position.y <= opponent.position.y {
+                axis.y = 1.;
+            }
+        }
+
+        // Reversed controls
+        if me.max_velocity_mutated().x < 0. {
+            axis.x *= -1.;
+        }
+
+        self.input = Input::from_axis(axis);
+        self.input
+    }
+
+    // Horizontal distance to an opponent falling on us
+    fn threat(&self, world: &World, me: &Player) -> Option<f64> {
+        // Nothing to fear while protected
+        if self.difficulty == Difficulty::Easy || me.has_shield() || me.is_ghost() || me.invulnerable > 0. {
+            return None;
+        }
+        world
+            .players
+            .iter()
+            .filter(|p| p.index != me.index && p.is_alive())
+            .filter(|p| p.position.y > me.position.y && p.velocity.y < 0.)
+            .map(|p| wrapped_dx(me.position.x, p.position.x))
+            .find(|dx| dx.abs() < Self::DANGER_ZONE)
+    }
+
+    // Horizontal distance to the closest falling bonus worth taking
+    fn wanted_bonus(&self, world: &World, me: &Player) -> Option<f64> {
+        if self.difficulty == Difficulty::Easy {
+            return None;
+        }
+        world
+            .bonuses
+            .iter()
+            .filter(|b| self.wants(&world.bonus_definitions[b.tag]))
+            .map(|b| wrapped_dx(me.position.x, b.position.x))
+            .min_by(|a, b| a.abs().partial_cmp(&b.abs()).unwrap())
+    }
+
+    // Points, speed and protection are good, being frozen or reversed is
+    // not, growing is a risky bet only hard bots take
+    fn wants(&self, definition: &BonusDefinition) -> bool {
+        let velocity = definition.velocity_factor;
+        if definition.score < 0 || velocity[0] <= 0. || velocity[1] <= 0. {
+            return false;
+        }
+        if definition.duration > 0. && definition.size_factor > 1. {
+            return self.difficulty == Difficulty::Hard;
+        }
+        definition.score > 0 || velocity[0] > 1. || definition.size_factor < 1. || definition.shield
+            || definition.ghost || definition.extra_jumps > 0
+    }
+}
+
+fn nearest_opponent<'a>(world: &'a World, me: &Player) -> Option<&'a Player> {
+    world
+        .players
+        .iter()
+        .filter(|p| p.index != me.index && p.is_alive())
+        .min_by(|a, b| {
+            let da = wrapped_dx(me.position.x, a.position.x).abs();
+            let db = wrapped_dx(me.position.x, b.position.x).abs();
+            da.partial_cmp(&db).unwrap()
+        })
+}
@@ -0,0 +1,181 @@
+use cgmath::Vector2;
+use cgmath::MetricSpace;
+
+// Axis-aligned box, position is the center
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub center: Vector2<f64>,
+    pub half_size: Vector2<f64>,
+}
+
+impl Aabb {
+    pub fn new(center: Vector2<f64>, size: Vector2<f64>) -> Aabb {
+        Aabb {
+            center: center,
+            half_size: size / 2.,
+        }
+    }
+
+    // Penetration depth on each axis, negative when apart on that axis
+    pub fn overlap(&self, other: &Aabb) -> Vector2<f64> {
+        let half_size = self.half_size + other.half_size;
+        Vector2::new(
+            half_size.x - (self.center.x - other.center.x).abs(),
+            half_size.y - (self.center.y - other.center.y).abs(),
+        )
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        let overlap = self.overlap(other);
+        overlap.x > 0. && overlap.y > 0.
+    }
+}
+
+// A moving box between the last two ticks
+#[derive(Debug, Clone, Copy)]
+pub struct Body {
+    pub previous: Aabb,
+    pub current: Aabb,
+    pub velocity: Vector2<f64>,
+}
+
+impl Body {
+    // Teleports and screen wrapping are not swept
+    const MAX_SWEEP: f64 = 0.25;
+
+    pub fn at(&self, t: f64) -> Aabb {
+        Aabb {
+            center: self.previous.center + (self.current.center - self.previous.center) * t,
+            half_size: self.current.half_size,
+        }
+    }
+
+    fn teleported(&self) -> bool {
+        self.previous.center.distance(self.current.center) > Self::MAX_SWEEP
+    }
+}
+
+// Time of impact between 0 (previous tick) and 1 (current tick) of two moving
+// boxes, so fast bodies cannot tunnel through each other
+pub fn sweep(a: &Body, b: &Body) -> Option<f64> {
+    if a.teleported() || b.teleported() {
+        return if a.current.intersects(&b.current) {
+            Some(1.)
+        } else {
+            None
+        };
+    }
+    if a.previous.intersects(&b.previous) {
+        return Some(0.);
+    }
+
+    // Move a relative to b, against b grown by a's size
+    let start = a.previous.center - b.previous.center;
+    let motion = (a.current.center - a.previous.center) - (b.current.center - b.previous.center);
+    let half_size = a.current.half_size + b.current.half_size;
+    let mut t_enter: f64 = 0.;
+    let mut t_exit: f64 = 1.;
+    for &(p, d, h) in [(start.x, motion.x, half_size.x), (start.y, motion.y, half_size.y)].iter() {
+        if d == 0. {
+            if p.abs() >= h {
+                return None;
+            }
+        } else {
+            let t1 = (-h - p) / d;
+            let t2 = (h - p) / d;
+            t_enter = t_enter.max(t1.min(t2));
+            t_exit = t_exit.min(t1.max(t2));
+        }
+    }
+    if t_enter < t_exit {
+        Some(t_enter)
+    } else {
+        None
+    }
+}
+
+// When two llamas collide, the one landing on the other stomps it: the
+// contact has to be vertical and the killer has to come down relatively to
+// the victim. Returns true if a stomps b, false if b stomps a.
+pub fn stomp(a: &Body, b: &Body) -> Option<bool> {
+    let t = match sweep(a, b) {
+        Some(t) => t,
+        None => return None,
+    };
+    let (box_a, box_b) = (a.at(t), b.at(t));
+    let overlap = box_a.overlap(&box_b);
+    if overlap.y > overlap.x {
+        // Side contact
+        return None;
+    }
+    let above = box_a.center.y - box_b.center.y;
+    let closing = a.velocity.y - b.velocity.y;
+    if above > 0. && closing < 0. {
+        Some(true)
+    } else if above < 0. && closing > 0. {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector2;
+    use super::*;
+
+    // A llama sized box moving from a point to another in one tick
+    fn body(from: (f64, f64), to: (f64, f64)) -> Body {
+        let size = Vector2::new(0.04, 0.04);
+        let (from, to) = (Vector2::new(from.0, from.1), Vector2::new(to.0, to.1));
+        Body {
+            previous: Aabb::new(from, size),
+            current: Aabb::new(to, size),
+            velocity: to - from,
+        }
+    }
+
+    #[test]
+    fn fast_bodies_do_not_tunnel() {
+        let (a, b) = (body((0., 0.1), (0., -0.1)), body((0., 0.), (0., 0.)));
+        assert!(!a.previous.intersects(&b.previous) && !a.current.intersects(&b.current));
+        let t = sweep(&a, &b).unwrap();
+        assert!((t - 0.3).abs() < 1e-9);
+        assert_eq!(stomp(&a, &b), Some(true));
+        assert_eq!(stomp(&b, &a), Some(false));
+    }
+
+    #[test]
+    fn touching_edges_do_not_collide() {
+        let b = body((0., 0.), (0., 0.));
+        assert_eq!(sweep(&body((0.04, 0.), (0.04, 0.)), &b), None);
+        assert_eq!(sweep(&body((0., 0.04), (0., 0.04)), &b), None);
+        // Sliding down along the side
+        assert_eq!(sweep(&body((0.04, 0.1), (0.04, -0.1)), &b), None);
+        // Coming to rest right on top
+        assert_eq!(sweep(&body((0., 0.1), (0., 0.04)), &b), None);
+    }
+
+    #[test]
+    fn side_contact_is_not_a_stomp() {
+        let (a, b) = (body((-0.1, 0.01), (0., 0.01)), body((0., 0.), (0., 0.)));
+        assert!(sweep(&a, &b).is_some());
+        assert_eq!(stomp(&a, &b), None);
+    }
+
+    #[test]
+    fn standing_inside_each_other_is_not_a_stomp() {
+        let (a, b) = (body((0., 0.01), (0., 0.01)), body((0., 0.), (0., 0.)));
+        assert_eq!(sweep(&a, &b), Some(0.));
+        assert_eq!(stomp(&a, &b), None);
+    }
+
+    #[test]
+    fn wrapping_around_the_screen_is_not_swept() {
+        let b = body((0., 0.), (0., 0.));
+        // Wrapped from one edge to the other, it never crossed the middle
+        assert_eq!(sweep(&body((0.49, 0.), (-0.49, 0.)), &b), None);
+        // Came out of the edge right onto it
+        assert_eq!(sweep(&body((-0.49, 0.), (0.01, 0.)), &b), Some(1.));
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::{Read, Write};
+use ggez::{Context, GameError, GameResult};
+use ggez::event::Keycode;
+use controls::{self, Action, Controls};
+
+// Key bindings, saved in the user config directory as lines like "p1.jump = Up"
+const CONTROLS_FILE: &'static str = "/controls.cfg";
+
+pub fn load_controls(ctx: &mut Context, count: usize) -> GameResult<Vec<Controls>> {
+    let mut controls: Vec<Controls> = (0..count).map(Controls::defaults).collect();
+    if !ctx.filesystem.exists(CONTROLS_FILE) {
+        return Ok(controls);
+    }
+    let mut content = String::new();
+    ctx.filesystem.open(CONTROLS_FILE)?.read_to_string(&mut content)?;
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| {
+            GameError::ConfigError(format!("{} line {}: {}", CONTROLS_FILE, n + 1, message))
+        };
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let key = parts.next().ok_or(error("expected <player>.<action> = <key>"))?.trim();
+        let mut name_parts = name.splitn(2, '.');
+        let player = name_parts.next().unwrap_or("");
+        let action = name_parts.next().unwrap_or("");
+        let index = match player.trim_left_matches('p').parse::<usize>() {
+            Ok(i) if i >= 1 && i <= count => i - 1,
+            _ => return Err(error(&format!("unknown player {}", player))),
+        };
+        let action = match Action::ALL.iter().find(|a| a.name() == action) {
+            Some(a) => *a,
+            None => return Err(error(&format!("unknown action {}", action))),
+        };
+        let keycode = Keycode::from_name(key).ok_or(error(&format!("unknown key {}", key)))?;
+        if let Some(reserved) = controls::reserved(keycode) {
+            return Err(error(&format!("{} is already used by {}", key, reserved)));
+        }
+        controls[index].set(action, keycode);
+    }
+    // Once all read, lines may swap keys between players
+    for (i, c) in controls.iter().enumerate() {
+        for action in Action::ALL.iter() {
+            let keycode = c.get(*action);
+            match controls::find(&controls, keycode) {
+                Some((j, a)) if j != i || a != *action => {
+                    return Err(GameError::ConfigError(format!(
+                        "{}: {} is used by both P{} {} and P{} {}",
+                        CONTROLS_FILE,
+                        keycode.name(),
+                        j + 1,
+                        a.name(),
+                        i + 1,
+                        action.name()
+                    )));
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(controls)
+}
+
+pub fn save_controls(ctx: &mut Context, controls: &[Controls]) -> GameResult<()> {
+    let mut content = String::new();
+    for (i, c) in controls.iter().enumerate() {
+        for action in Action::ALL.iter() {
+            content.push_str(&format!("p{}.{} = {}\n", i + 1, action.name(), c.get(*action).name()));
+        }
+    }
+    ctx.filesystem.create(CONTROLS_FILE)?.write_all(content.as_bytes())?;
+    Ok(())
+}
@@ -1,9 +1,104 @@
 use ggez::event;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Up,
+    Left,
+    Right,
+}
+
+impl Action {
+    pub const ALL: [Action; 3] = [Action::Up, Action::Left, Action::Right];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::Up => "jump",
+            Action::Left => "left",
+            Action::Right => "right",
+        }
+    }
+}
+
+// Keys the game itself listens to, players cannot move with them
+pub const RESERVED: [(event::Keycode, &'static str); 8] = [
+    (event::Keycode::P, "pause"),
+    (event::Keycode::F5, "quick save"),
+    (event::Keycode::F9, "quick load"),
+    (event::Keycode::F10, "integer scaling"),
+    (event::Keycode::F11, "fullscreen"),
+    (event::Keycode::Escape, "quit"),
+    (event::Keycode::Space, "replays and menus"),
+    (event::Keycode::Backspace, "menus"),
+];
+
+// What the game uses a reserved key for
+pub fn reserved(keycode: event::Keycode) -> Option<&'static str> {
+    RESERVED.iter().find(|&&(k, _)| k == keycode).map(|&(_, name)| name)
+}
+
 // Controls
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Controls {
     pub up: event::Keycode,
     pub left: event::Keycode,
     pub right: event::Keycode,
-}
\ No newline at end of file
+}
+
+impl Controls {
+    // Default keyboard layout of each player slot
+    pub fn defaults(index: usize) -> Controls {
+        use ggez::event::Keycode::*;
+        let (up, left, right) = match index {
+            0 => (Up, Left, Right),
+            1 => (E, S, F),
+            2 => (I, J, L),
+            3 => (Kp8, Kp4, Kp6),
+            4 => (Num2, Num1, Num3),
+            5 => (Num9, Num8, Num0),
+            6 => (B, V, N),
+            _ => (Y, T, U),
+        };
+        Controls {
+            up: up,
+            left: left,
+            right: right,
+        }
+    }
+
+    pub fn get(&self, action: Action) -> event::Keycode {
+        match action {
+            Action::Up => self.up,
+            Action::Left => self.left,
+            Action::Right => self.right,
+        }
+    }
+
+    pub fn set(&mut self, action: Action, keycode: event::Keycode) {
+        match action {
+            Action::Up => self.up = keycode,
+            Action::Left => self.left = keycode,
+            Action::Right => self.right = keycode,
+        }
+    }
+
+    // "P1: jump Up, left Left, right Right"
+    pub fn describe(&self, index: usize) -> String {
+        let keys: Vec<String> = Action::ALL
+            .iter()
+            .map(|a| format!("{} {}", a.name(), self.get(*a).name()))
+            .collect();
+        format!("P{}: {}", index + 1, keys.join(", "))
+    }
+}
+
+// Which player and action a key is bound to
+pub fn find(controls: &[Controls], keycode: event::Keycode) -> Option<(usize, Action)> {
+    for (i, c) in controls.iter().enumerate() {
+        for action in Action::ALL.iter() {
+            if c.get(*action) == keycode {
+                return Some((i, *action));
+            }
+        }
+    }
+    None
+}
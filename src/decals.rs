@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+use ggez::graphics;
+use ggez::{Context, GameResult};
+use assets::Assets;
+use display::Screen;
+use helpers;
+use particles::{ParticleDefinition, Stain};
+
+// Stains stay where they fell and fade over the match, drawn over the
+// background every frame. Memory stays the same whatever the carnage: past
+// MAX_STAINS, the oldest ones go first.
+pub struct Decals {
+    // With their age in seconds, oldest first
+    stains: VecDeque<(Stain, f64)>,
+}
+
+impl Decals {
+    pub const MAX_STAINS: usize = 512;
+    // Seconds until a stain is gone
+    const LIFETIME: f64 = 60.;
+
+    pub fn new() -> Decals {
+        Decals {
+            stains: VecDeque::new(),
+        }
+    }
+
+    // Back to the bare background, on a new round or a new level
+    pub fn clear(&mut self) {
+        self.stains.clear();
+    }
+
+    pub fn add(&mut self, stain: Stain) {
+        if self.stains.len() == Self::MAX_STAINS {
+            self.stains.pop_front();
+        }
+        self.stains.push_back((stain, 0.));
+    }
+
+    // Follows the simulation, not the frame rate
+    pub fn update(&mut self, seconds: f64) {
+        for stain in &mut self.stains {
+            stain.1 += seconds;
+        }
+        while self.stains.front().map_or(false, |s| s.1 >= Self::LIFETIME) {
+            self.stains.pop_front();
+        }
+    }
+
+    // The background, then the stains on it, as seen from the view
+    pub fn draw(
+        &self,
+        ctx: &mut Context,
+        background: &graphics::Image,
+        definitions: &[ParticleDefinition],
+        view: &Screen,
+        assets: &Assets,
+    ) -> GameResult<()> {
+        helpers::draw_full_screen(ctx, background, view)?;
+        for &(ref stain, age) in &self.stains {
+            let opacity = 1. - age / Self::LIFETIME;
+            stain.draw(ctx, &definitions[stain.tag], opacity as f32, view, assets)?;
+        }
+        Ok(())
+    }
+}
@@ -1,42 +1,155 @@
 use cgmath::Vector2;
 
 // Screen
+#[derive(Debug, Clone)]
 pub struct Screen {
+    // Window, in pixels
+    width: u32,
+    height: u32,
+    // The arena, centered in the window with bars around if the ratio differs
     view_width: u32,
     view_height: u32,
+    // Only whole multiples of the base size, the pixel art stays crisp
+    integer_scaling: bool,
+    // Point of the world at the middle of the screen, and magnification
+    center: Vector2<f64>,
+    zoom: f64,
 }
 
 impl Screen {
+    // Base size, the window starts with it
     pub const WIDTH: u32 = 640;
     pub const HEIGHT: u32 = 400;
     const RATIO: (u32, u32) = (8, 5);
 
-    pub fn new() -> Screen {
-        let cell_by_width = Screen::WIDTH as f32 / Screen::RATIO.0 as f32;
-        let cell_by_height = Screen::HEIGHT as f32 / Screen::RATIO.1 as f32;
+    pub fn new(width: u32, height: u32, integer_scaling: bool) -> Screen {
+        let mut screen = Screen {
+            width: width,
+            height: height,
+            view_width: width,
+            view_height: height,
+            integer_scaling: integer_scaling,
+            center: Vector2::new(0., 0.),
+            zoom: 1.,
+        };
+        screen.resize(width, height);
+        screen
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        let scale = (width / Screen::WIDTH).min(height / Screen::HEIGHT);
+        // A window smaller than the base size gets whatever fits
+        if self.integer_scaling && scale > 0 {
+            self.view_width = Screen::WIDTH * scale;
+            self.view_height = Screen::HEIGHT * scale;
+            return;
+        }
+        let cell_by_width = width as f32 / Screen::RATIO.0 as f32;
+        let cell_by_height = height as f32 / Screen::RATIO.1 as f32;
         let cell_size = if cell_by_width > cell_by_height {
-            cell_by_width
+            cell_by_height
         } else {
             cell_by_width
         };
-        let view_width = (cell_size * Screen::RATIO.0 as f32) as u32;
-        let view_height = (cell_size * Screen::RATIO.1 as f32) as u32;
+        self.view_width = (cell_size * Screen::RATIO.0 as f32) as u32;
+        self.view_height = (cell_size * Screen::RATIO.1 as f32) as u32;
+    }
+
+    pub fn is_integer_scaling(&self) -> bool {
+        self.integer_scaling
+    }
+
+    pub fn set_integer_scaling(&mut self, integer_scaling: bool) {
+        self.integer_scaling = integer_scaling;
+        let (width, height) = (self.width, self.height);
+        self.resize(width, height);
+    }
+
+    // The same screen, looking at the world through a camera. The view stays
+    // within the world, there is nothing to see around it.
+    pub fn view(&self, center: Vector2<f64>, zoom: f64) -> Screen {
+        let zoom = zoom.max(1.);
         Screen {
-            view_width: view_width,
-            view_height: view_height,
+            center: within_world(center, zoom),
+            zoom: zoom,
+            ..self.clone()
         }
     }
 
+    // Pixels per pixel of the base size, for texts
+    pub fn pixel_scale(&self) -> f64 {
+        self.view_width as f64 / Screen::WIDTH as f64
+    }
+
+    // Centers and sizes, in pixels, of the window parts outside the arena
+    pub fn bars(&self) -> Vec<(Vector2<f64>, Vector2<f64>)> {
+        let (width, height) = (self.width as f64, self.height as f64);
+        let side = (width - self.view_width as f64) / 2.;
+        let top = (height - self.view_height as f64) / 2.;
+        let mut bars = vec![];
+        if side > 0. {
+            bars.push((Vector2::new(side / 2., height / 2.), Vector2::new(side, height)));
+            bars.push((Vector2::new(width - side / 2., height / 2.), Vector2::new(side, height)));
+        }
+        if top > 0. {
+            bars.push((Vector2::new(width / 2., top / 2.), Vector2::new(width, top)));
+            bars.push((Vector2::new(width / 2., height - top / 2.), Vector2::new(width, top)));
+        }
+        bars
+    }
+
     pub fn position_to_pixel(&self, postion: Vector2<f64>) -> Vector2<f64> {
-        Vector2::new(self.view_width as f64 * postion.x + self.view_width as f64 / 2.,
-                     self.view_height as f64 * -postion.y + self.view_height as f64 / 2.)
+        let postion = (postion - self.center) * self.zoom;
+        Vector2::new(self.view_width as f64 * postion.x + self.width as f64 / 2.,
+                     self.view_height as f64 * -postion.y + self.height as f64 / 2.)
 
     }
 
     pub fn size_to_pixel(&self, size: Vector2<f64>) -> Vector2<f64> {
-        Vector2::new(self.view_width as f64 * size.x,
-                     self.view_height as f64 * size.y)
+        Vector2::new(self.view_width as f64 * size.x * self.zoom,
+                     self.view_height as f64 * size.y * self.zoom)
+
+    }
+}
+
+// Where spectators look, free or following a llama
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    pub center: Vector2<f64>,
+    pub zoom: f64,
+    pub follow: Option<usize>,
+}
 
+impl Camera {
+    const MAX_ZOOM: f64 = 4.;
+    const ZOOM_STEP: f64 = 1.25;
+    // Screens per key press, whatever the zoom
+    const PAN_STEP: f64 = 0.1;
+
+    pub fn new() -> Camera {
+        Camera {
+            center: Vector2::new(0., 0.),
+            zoom: 1.,
+            follow: None,
+        }
+    }
+
+    // Moving lets go of the followed llama, from where it was
+    pub fn pan(&mut self, from: Vector2<f64>, direction: Vector2<f64>) {
+        self.follow = None;
+        self.center = within_world(from + direction * (Self::PAN_STEP / self.zoom), self.zoom);
+    }
+
+    // Positive steps zoom in
+    pub fn zoom(&mut self, steps: i32) {
+        self.zoom = (self.zoom * Self::ZOOM_STEP.powi(steps)).max(1.).min(Self::MAX_ZOOM);
     }
 }
 
+// Closest center showing nothing outside the world
+fn within_world(center: Vector2<f64>, zoom: f64) -> Vector2<f64> {
+    let margin = 0.5 - 0.5 / zoom;
+    Vector2::new(center.x.max(-margin).min(margin), center.y.max(-margin).min(margin))
+}
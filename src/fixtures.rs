@@ -0,0 +1,32 @@
+use bonus::BonusDefinition;
+use level::Level;
+use particles::ParticleDefinition;
+use rules::Rules;
+use world::World;
+
+// What tests play with: the shipped definitions and the first level, built
+// in so that they do not depend on where they run from
+
+pub fn level() -> Level {
+    Level::parse("meadow", include_str!("../resources/levels/meadow.txt")).unwrap()
+}
+
+// Nobody wins, the match goes on as long as the test wants
+pub fn endless() -> Rules {
+    Rules {
+        score_limit: 0,
+        time_limit: 0,
+        rounds: 1,
+    }
+}
+
+pub fn world(seed: u64, players: usize) -> World {
+    World::new(
+        seed,
+        players,
+        level(),
+        endless(),
+        BonusDefinition::parse(include_str!("../resources/bonuses.toml")).unwrap(),
+        ParticleDefinition::parse(include_str!("../resources/particles.toml")).unwrap(),
+    )
+}
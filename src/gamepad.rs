@@ -0,0 +1,123 @@
+use cgmath::Vector2;
+use ggez::Context;
+use ggez::event::{Axis, Button};
+use ggez::input::GameController;
+
+// A game controller and the player slot it drives
+#[derive(Debug)]
+pub struct Gamepad {
+    pub id: i32,
+    pub slot: usize,
+    stick: f64,
+    dpad_left: bool,
+    dpad_right: bool,
+    jump: bool,
+}
+
+impl Gamepad {
+    const DEAD_ZONE: f64 = 0.25;
+
+    pub fn new(id: i32, slot: usize) -> Gamepad {
+        Gamepad {
+            id: id,
+            slot: slot,
+            stick: 0.,
+            dpad_left: false,
+            dpad_right: false,
+            jump: false,
+        }
+    }
+
+    // Same range as the keyboard input axis
+    pub fn axis(&self) -> Vector2<f64> {
+        // Both directions held cancel out
+        let dpad = self.dpad_right as i32 - self.dpad_left as i32;
+        let x = if dpad != 0 { dpad as f64 } else { self.stick };
+        Vector2::new(x, if self.jump { 1. } else { 0. })
+    }
+
+    pub fn button(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::A => self.jump = pressed,
+            Button::DPadLeft => self.dpad_left = pressed,
+            Button::DPadRight => self.dpad_right = pressed,
+            _ => (),
+        }
+    }
+
+    pub fn motion(&mut self, axis: Axis, value: i16) {
+        if let Axis::LeftX = axis {
+            let value = value as f64 / i16::max_value() as f64;
+            self.stick = if value.abs() < Self::DEAD_ZONE {
+                0.
+            } else {
+                value.max(-1.).min(1.)
+            };
+        }
+    }
+}
+
+// Controllers the game keeps open. ggez opens the ones there at start only,
+// the ones plugged in later send no event until opened.
+pub struct Controllers {
+    open: Vec<GameController>,
+    // Joysticks seen last time, the list is only gone through when it changes
+    joysticks: u32,
+}
+
+impl Controllers {
+    pub fn new() -> Controllers {
+        Controllers {
+            open: vec![],
+            joysticks: 0,
+        }
+    }
+
+    // Opens the controllers just plugged in, returns the ids of the ones
+    // unplugged since last time
+    pub fn update(&mut self, ctx: &mut Context) -> Vec<i32> {
+        let mut removed = vec![];
+        self.open.retain(|c| {
+            if !c.attached() {
+                removed.push(c.instance_id());
+            }
+            c.attached()
+        });
+        let subsystem = match ctx.sdl_context.game_controller() {
+            Ok(subsystem) => subsystem,
+            Err(_) => return removed,
+        };
+        let count = subsystem.num_joysticks().unwrap_or(0);
+        if count != self.joysticks {
+            self.joysticks = count;
+            for i in (0..count).filter(|&i| subsystem.is_game_controller(i)) {
+                // Opening one already open gives another handle on it, only
+                // the first one is kept
+                if let Ok(controller) = subsystem.open(i) {
+                    if !self.open.iter().any(|c| c.instance_id() == controller.instance_id()) {
+                        self.open.push(controller);
+                    }
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ggez::event::Button;
+    use super::Gamepad;
+
+    #[test]
+    fn releasing_one_direction_keeps_the_other() {
+        let mut gamepad = Gamepad::new(0, 0);
+        gamepad.button(Button::DPadLeft, true);
+        gamepad.button(Button::DPadRight, true);
+        assert_eq!(gamepad.axis().x, 0.);
+        gamepad.button(Button::DPadRight, false);
+        assert_eq!(gamepad.axis().x, -1.);
+        gamepad.button(Button::DPadLeft, false);
+        assert_eq!(gamepad.axis().x, 0.);
+    }
+}
@@ -1,119 +1,152 @@
 use ggez::graphics;
 use ggez::{Context, GameResult};
 use cgmath::Vector2;
+use cgmath::MetricSpace;
 use animation::{LeftRightImage, PlayerAnimation, PlayerAnimationStatus};
 use player::Player;
-use assets::Assets;
 use display::Screen;
-use particles::Blood;
-use rand;
+use rand::Rng;
+use rng::GameRng;
+use level::{Level, PlatformKind};
+use textures::{Texture, TextureCache};
 
 
 
-pub fn player1_animation(ctx: &mut Context) -> GameResult<PlayerAnimation> {
+pub fn player1_animation(ctx: &mut Context, textures: &mut TextureCache) -> GameResult<PlayerAnimation> {
     let r = PlayerAnimation {
         walk: [
             LeftRightImage {
-                left: sprite(ctx, "/sprite_02.png")?,
-                right: sprite(ctx, "/sprite_07.png")?,
+                left: sprite(ctx, textures, "/sprite_02.png")?,
+                right: sprite(ctx, textures, "/sprite_07.png")?,
             },
             LeftRightImage {
-                left: sprite(ctx, "/sprite_03.png")?,
-                right: sprite(ctx, "/sprite_08.png")?,
+                left: sprite(ctx, textures, "/sprite_03.png")?,
+                right: sprite(ctx, textures, "/sprite_08.png")?,
             },
         ],
         jump: LeftRightImage {
-            left: sprite(ctx, "/sprite_04.png")?,
-            right: sprite(ctx, "/sprite_09.png")?,
+            left: sprite(ctx, textures, "/sprite_04.png")?,
+            right: sprite(ctx, textures, "/sprite_09.png")?,
         },
         fall: LeftRightImage {
-            left: sprite(ctx, "/sprite_05.png")?,
-            right: sprite(ctx, "/sprite_10.png")?,
+            left: sprite(ctx, textures, "/sprite_05.png")?,
+            right: sprite(ctx, textures, "/sprite_10.png")?,
         },
         stand: LeftRightImage {
-            left: sprite(ctx, "/sprite_01.png")?,
-            right: sprite(ctx, "/sprite_06.png")?,
+            left: sprite(ctx, textures, "/sprite_01.png")?,
+            right: sprite(ctx, textures, "/sprite_06.png")?,
         },
-        time: 0.,
     };
     Ok(r)
 }
 
-pub fn player2_animation(ctx: &mut Context) -> GameResult<PlayerAnimation> {
+pub fn player2_animation(ctx: &mut Context, textures: &mut TextureCache) -> GameResult<PlayerAnimation> {
     let r = PlayerAnimation {
         walk: [
             LeftRightImage {
-                left: sprite(ctx, "/sprite_13.png")?,
-                right: sprite(ctx, "/sprite_18.png")?,
+                left: sprite(ctx, textures, "/sprite_13.png")?,
+                right: sprite(ctx, textures, "/sprite_18.png")?,
             },
             LeftRightImage {
-                left: sprite(ctx, "/sprite_14.png")?,
-                right: sprite(ctx, "/sprite_19.png")?,
+                left: sprite(ctx, textures, "/sprite_14.png")?,
+                right: sprite(ctx, textures, "/sprite_19.png")?,
             },
         ],
         jump: LeftRightImage {
-            left: sprite(ctx, "/sprite_15.png")?,
-            right: sprite(ctx, "/sprite_20.png")?,
+            left: sprite(ctx, textures, "/sprite_15.png")?,
+            right: sprite(ctx, textures, "/sprite_20.png")?,
         },
         fall: LeftRightImage {
-            left: sprite(ctx, "/sprite_16.png")?,
-            right: sprite(ctx, "/sprite_21.png")?,
+            left: sprite(ctx, textures, "/sprite_16.png")?,
+            right: sprite(ctx, textures, "/sprite_21.png")?,
         },
         stand: LeftRightImage {
-            left: sprite(ctx, "/sprite_12.png")?,
-            right: sprite(ctx, "/sprite_17.png")?,
+            left: sprite(ctx, textures, "/sprite_12.png")?,
+            right: sprite(ctx, textures, "/sprite_17.png")?,
         },
-        time: 0.,
     };
     Ok(r)
 }
 
-pub fn sprite(ctx: &mut Context, s: &str) -> GameResult<graphics::Image> {
-    let mut llama_s = graphics::Image::new(ctx, s)?;
-    llama_s.set_filter(graphics::FilterMode::Nearest);
-    Ok(llama_s)
+pub fn sprite(ctx: &mut Context, textures: &mut TextureCache, s: &str) -> GameResult<Texture> {
+    textures.load(ctx, s)
 }
 
-pub fn player_image(player: &mut Player) -> &graphics::Image {
-    let status = if player.velocity.y < 0. {
-        PlayerAnimationStatus::Falling
-    } else if player.velocity.y > 0. {
-        PlayerAnimationStatus::Jumping
-    } else if player.velocity.x != 0. {
-        PlayerAnimationStatus::Walking
-    } else {
-        PlayerAnimationStatus::Standing
-    };
-    match status {
+pub fn player_image(player: &Player, animation: &PlayerAnimation) -> Texture {
+    match player.animation_status() {
         PlayerAnimationStatus::Walking => {
-            player.animation.time += 1. / 60.; // TODO
-            if player.animation.time > PlayerAnimation::WALK_ANIMATION_CYCLE * 2. {
-                player.animation.time = 0.;
-                &player.animation.walk[0].face(&player.facing)
-            } else if player.animation.time > PlayerAnimation::WALK_ANIMATION_CYCLE {
-                &player.animation.walk[0].face(&player.facing)
+            if player.animation_time > PlayerAnimation::WALK_ANIMATION_CYCLE {
+                animation.walk[0].face(&player.facing)
             } else {
-                &player.animation.walk[1].face(&player.facing)
+                animation.walk[1].face(&player.facing)
             }
         }
-        PlayerAnimationStatus::Standing => &player.animation.stand.face(&player.facing),
-        PlayerAnimationStatus::Jumping => &player.animation.jump.face(&player.facing),
-        PlayerAnimationStatus::Falling => &player.animation.fall.face(&player.facing),
+        PlayerAnimationStatus::Standing => animation.stand.face(&player.facing),
+        PlayerAnimationStatus::Jumping => animation.jump.face(&player.facing),
+        PlayerAnimationStatus::Falling => animation.fall.face(&player.facing),
     }
 }
 
-pub fn kill(players: &mut [Player; 2], killer_index: usize, victim_index: usize) {
+pub fn kill(players: &mut [Player], killer_index: usize, victim_index: usize) {
     players[killer_index].score += 1;
     players[killer_index].velocity.y *= -1.0;
-    players[victim_index].position = random_position();
+    players[victim_index].die();
 }
 
-pub fn score_text(
-    ctx: &mut Context,
-    score: u32,
-    assets: &mut Assets,
-) -> GameResult<graphics::Text> {
-    graphics::Text::new(ctx, &format!("{}", score), &assets.font)
+// The spawn point whose closest opponent is the farthest away
+pub fn farthest_spawn(level: &Level, players: &[Player], index: usize) -> Vector2<f64> {
+    let distance_to_opponents = |spawn: &Vector2<f64>| {
+        players
+            .iter()
+            .filter(|p| p.index != index && p.is_alive())
+            .map(|p| {
+                let dx = wrapped_dx(spawn.x, p.position.x);
+                let dy = p.position.y - Player::FEET - spawn.y;
+                dx * dx + dy * dy
+            })
+            .fold(::std::f64::INFINITY, f64::min)
+    };
+    let mut best = level.spawns[0];
+    let mut best_distance = distance_to_opponents(&best);
+    for spawn in &level.spawns[1..] {
+        let distance = distance_to_opponents(spawn);
+        if distance > best_distance {
+            best = *spawn;
+            best_distance = distance;
+        }
+    }
+    best
+}
+
+// Swaps places with a random living opponent, returns who that was
+pub fn teleport(players: &mut [Player], index: usize, rng: &mut GameRng) -> Option<usize> {
+    let opponents: Vec<usize> = players
+        .iter()
+        .filter(|p| p.index != index && p.is_alive())
+        .map(|p| p.index)
+        .collect();
+    if opponents.is_empty() {
+        return None;
+    }
+    let other = opponents[rng.gen_range(0, opponents.len())];
+    let (a, b) = (players[index].position, players[other].position);
+    players[index].position = b;
+    players[index].previous_position = b;
+    players[other].position = a;
+    players[other].previous_position = a;
+    Some(other)
+}
+
+// Shortest horizontal distance, the arena wraps around
+pub fn wrapped_dx(from: f64, to: f64) -> f64 {
+    let dx = to - from;
+    if dx > 0.5 {
+        dx - 1.
+    } else if dx < -0.5 {
+        dx + 1.
+    } else {
+        dx
+    }
 }
 
 pub fn quick_draw(
@@ -123,28 +156,13 @@ pub fn quick_draw(
     screen: &Screen,
 ) -> GameResult<()> {
     let p = point_from_position(Vector2::new(position.0, position.1), screen);
-    Ok(graphics::draw(ctx, d, p, 0.)?)
-}
-
-pub fn draw_blood(
-    ctx: &mut Context,
-    blood: &Blood,
-    screen: &Screen,
-    assets: &mut Assets,
-) -> GameResult<()> {
-    let blood_image = &assets.blood;
-
-    graphics::draw_ex(
-        ctx,
-        blood_image,
-        graphics::DrawParam {
-            dest: point_from_position(blood.position, screen),
-            scale: scale(blood.size, screen, blood_image),
-            rotation: blood.velocity.y.atan2(blood.velocity.x * -1.0) as f32,
-            ..Default::default()
-        },
-    )?;
-    Ok(())
+    let scale = screen.pixel_scale() as f32;
+    let draw_param = graphics::DrawParam {
+        dest: p,
+        scale: graphics::Point { x: scale, y: scale },
+        ..Default::default()
+    };
+    Ok(graphics::draw_ex(ctx, d, draw_param)?)
 }
 
 pub fn point_from_position(position: Vector2<f64>, screen: &Screen) -> graphics::Point {
@@ -166,21 +184,6 @@ pub fn scale(size: Vector2<f64>, screen: &Screen, image: &graphics::Image) -> gr
     }
 }
 
-pub fn random_position() -> Vector2<f64> {
-    Vector2::new(rand::random::<f64>() - 0.5, ::GROUND_Y)
-}
-
-pub fn random_blood_particle(position: Vector2<f64>) -> Blood {
-    Blood {
-        position: position,
-        size: Vector2::new(0.025, 0.025),
-        velocity: Vector2::new(
-            (rand::random::<f64>() - 0.5) / 50.,
-            rand::random::<f64>() / 40.,
-        ),
-    }
-}
-
 pub fn draw_full_screen(
     ctx: &mut Context,
     image: &graphics::Image,
@@ -200,12 +203,78 @@ pub fn draw_full_screen(
     Ok(())
 }
 
-pub fn is_on_top(first: Vector2<f64>, second: Vector2<f64>, screen: &Screen) -> bool {
-    screen.position_to_pixel(second).y as i32 > screen.position_to_pixel(first).y as i32
+pub fn background(ctx: &mut Context, level: &Level) -> GameResult<graphics::Image> {
+    let mut image = graphics::Image::new(ctx, &level.background)?;
+    image.set_filter(graphics::FilterMode::Nearest);
+    Ok(image)
+}
+
+// The floor is part of the background, other platforms are drawn on top of it
+pub fn draw_platforms(ctx: &mut Context, level: &Level, screen: &Screen) -> GameResult<()> {
+    for platform in &level.platforms {
+        let (color, bottom) = match platform.kind {
+            PlatformKind::Floor => continue,
+            PlatformKind::Solid => (graphics::Color::from((92, 64, 51)), platform.bottom),
+            // Drawn thinner, a hint that they can be jumped through
+            PlatformKind::OneWay => (
+                graphics::Color::from((160, 120, 80)),
+                platform.top - (platform.top - platform.bottom) / 3.,
+            ),
+        };
+        let center = Vector2::new((platform.left + platform.right) / 2., (platform.top + bottom) / 2.);
+        let center = screen.position_to_pixel(center);
+        let size = screen.size_to_pixel(Vector2::new(platform.right - platform.left, platform.top - bottom));
+        let rect = graphics::Rect::new(center.x as f32, center.y as f32, size.x as f32, size.y as f32);
+        graphics::set_color(ctx, color)?;
+        graphics::rectangle(ctx, graphics::DrawMode::Fill, rect)?;
+    }
+    graphics::set_color(ctx, (255, 255, 255).into())?;
+    Ok(())
+}
+
+// One shrinking bar per active mutation, hanging below the given point
+pub fn draw_mutation_timers(ctx: &mut Context, player: &Player, top: Vector2<f64>, screen: &Screen) -> GameResult<()> {
+    const WIDTH: f64 = 0.08;
+    const HEIGHT: f64 = 0.008;
+    let active = player.mutations.iter().filter(|m| m.duration > 0.);
+    for (i, mutation) in active.enumerate() {
+        let width = WIDTH * mutation.remaining();
+        // Anchored on the left so that it shrinks towards it
+        let left = top.x - WIDTH / 2.;
+        let center = Vector2::new(left + width / 2., top.y - (i as f64 + 0.5) * HEIGHT * 1.5);
+        let center = screen.position_to_pixel(center);
+        let size = screen.size_to_pixel(Vector2::new(width, HEIGHT));
+        let rect = graphics::Rect::new(center.x as f32, center.y as f32, size.x as f32, size.y as f32);
+        graphics::set_color(ctx, mutation.tint.into())?;
+        graphics::rectangle(ctx, graphics::DrawMode::Fill, rect)?;
+    }
+    graphics::set_color(ctx, (255, 255, 255).into())?;
+    Ok(())
+}
+
+// Position between the last two simulation ticks, teleports and screen
+// wrapping are not interpolated
+pub fn interpolate(previous: Vector2<f64>, current: Vector2<f64>, alpha: f64) -> Vector2<f64> {
+    if previous.distance(current) > 0.25 {
+        current
+    } else {
+        previous + (current - previous) * alpha
+    }
+}
+
+// Nothing goes past the arena, whatever the window ratio
+pub fn draw_letterbox(ctx: &mut Context, screen: &Screen) -> GameResult<()> {
+    graphics::set_color(ctx, graphics::BLACK)?;
+    for (center, size) in screen.bars() {
+        let rect = graphics::Rect::new(center.x as f32, center.y as f32, size.x as f32, size.y as f32);
+        graphics::rectangle(ctx, graphics::DrawMode::Fill, rect)?;
+    }
+    graphics::set_color(ctx, (255, 255, 255).into())?;
+    Ok(())
 }
 
-pub fn transparent_layer(ctx: &mut Context, screen: &Screen) -> GameResult<()> {
-    graphics::set_color(ctx, graphics::Color::new(0., 0., 0., 0.75))?;
+pub fn transparent_layer(ctx: &mut Context, screen: &Screen, opacity: f32) -> GameResult<()> {
+    graphics::set_color(ctx, graphics::Color::new(0., 0., 0., opacity))?;
     let center = screen.position_to_pixel(Vector2::new(0., 0.));
     let size = screen.size_to_pixel(Vector2::new(1., 1.));
     let rect = graphics::Rect::new(
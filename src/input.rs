@@ -0,0 +1,24 @@
+use cgmath::Vector2;
+
+// Input axis of one player for one tick, quantized so that recorded or
+// networked inputs replay exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Input {
+    pub x: i8,
+    pub y: i8,
+}
+
+impl Input {
+    const AXIS_MAX: f64 = 127.;
+
+    pub fn from_axis(axis: Vector2<f64>) -> Input {
+        Input {
+            x: (axis.x.max(-1.).min(1.) * Self::AXIS_MAX).round() as i8,
+            y: (axis.y.max(-1.).min(1.) * Self::AXIS_MAX).round() as i8,
+        }
+    }
+
+    pub fn axis(&self) -> Vector2<f64> {
+        Vector2::new(self.x as f64 / Self::AXIS_MAX, self.y as f64 / Self::AXIS_MAX)
+    }
+}
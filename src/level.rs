@@ -0,0 +1,273 @@
+use std::io::Read;
+use cgmath::Vector2;
+use ggez::{Context, GameError, GameResult};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlatformKind {
+    // Blocks from every side
+    Solid,
+    // Same as solid but already painted on the background
+    Floor,
+    // Can be jumped through from below
+    OneWay,
+}
+
+#[derive(Debug, Clone)]
+pub struct Platform {
+    pub kind: PlatformKind,
+    pub left: f64,
+    pub right: f64,
+    pub top: f64,
+    pub bottom: f64,
+}
+
+impl Platform {
+    fn spans(&self, left: f64, right: f64) -> bool {
+        left < self.right && right > self.left
+    }
+
+    fn is_solid(&self) -> bool {
+        self.kind != PlatformKind::OneWay
+    }
+}
+
+// A level is a grid of tiles covering the whole arena, loaded from
+// resources/levels/<name>.txt:
+//
+//   ; comment, '#' is a solid tile
+//   background = /bg10.png
+//   then ROWS lines of COLUMNS tiles: '#' solid, '_' floor, '=' one-way, '.' empty,
+//   'S' empty with a spawn point standing on the tile below
+#[derive(Debug, Clone)]
+pub struct Level {
+    pub name: String,
+    pub background: String,
+    pub platforms: Vec<Platform>,
+    // Where the feet of respawning llamas go
+    pub spawns: Vec<Vector2<f64>>,
+}
+
+impl Level {
+    pub const COLUMNS: usize = 32;
+    pub const ROWS: usize = 20;
+    const EPSILON: f64 = 1e-6;
+    const TILES: &'static str = "#_=.S";
+    // Spawn points spread over the ground when the file has none
+    const DEFAULT_SPAWNS: usize = 8;
+
+    pub fn load(ctx: &mut Context, name: &str) -> GameResult<Level> {
+        let mut content = String::new();
+        ctx.filesystem
+            .open(format!("/levels/{}.txt", name))?
+            .read_to_string(&mut content)?;
+        Level::parse(name, &content)
+            .map_err(|e| GameError::ResourceLoadError(format!("level {}: {}", name, e)))
+    }
+
+    // Names of the levels listed in resources/levels/index.txt
+    pub fn list(ctx: &mut Context) -> GameResult<Vec<String>> {
+        let mut content = String::new();
+        ctx.filesystem.open("/levels/index.txt")?.read_to_string(&mut content)?;
+        Ok(content
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty() && !l.starts_with(';'))
+            .collect())
+    }
+
+    pub fn parse(name: &str, content: &str) -> Result<Level, String> {
+        let mut background = None;
+        let mut rows = vec![];
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if line.chars().all(|c| Self::TILES.contains(c)) {
+                if line.chars().count() != Self::COLUMNS {
+                    return Err(format!("line {}: expected {} tiles", n + 1, Self::COLUMNS));
+                }
+                rows.push((n, line));
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(format!("line {}: expected tiles or <setting> = <value>", n + 1)),
+            };
+            match key {
+                "background" => background = Some(value.to_string()),
+                _ => return Err(format!("line {}: unknown setting {}", n + 1, key)),
+            }
+        }
+        if rows.len() != Self::ROWS {
+            return Err(format!("expected {} rows of tiles, found {}", Self::ROWS, rows.len()));
+        }
+
+        // Consecutive tiles of a row are merged into one platform
+        let mut platforms = vec![];
+        let mut spawns = vec![];
+        for (row, &(_, line)) in rows.iter().enumerate() {
+            let mut current: Option<Platform> = None;
+            for (column, c) in line.chars().enumerate() {
+                let kind = match c {
+                    '#' => Some(PlatformKind::Solid),
+                    '_' => Some(PlatformKind::Floor),
+                    '=' => Some(PlatformKind::OneWay),
+                    _ => None,
+                };
+                let left = column as f64 / Self::COLUMNS as f64 - 0.5;
+                let right = (column + 1) as f64 / Self::COLUMNS as f64 - 0.5;
+                if c == 'S' {
+                    spawns.push(Vector2::new((left + right) / 2., 0.5 - (row + 1) as f64 / Self::ROWS as f64));
+                }
+                match (current.take(), kind) {
+                    (Some(mut p), Some(kind)) if p.kind == kind => {
+                        p.right = right;
+                        current = Some(p);
+                    }
+                    (previous, kind) => {
+                        platforms.extend(previous);
+                        current = kind.map(|kind| Platform {
+                            kind: kind,
+                            left: left,
+                            right: right,
+                            top: 0.5 - row as f64 / Self::ROWS as f64,
+                            bottom: 0.5 - (row + 1) as f64 / Self::ROWS as f64,
+                        });
+                    }
+                }
+            }
+            platforms.extend(current);
+        }
+
+        let mut level = Level {
+            name: name.to_string(),
+            background: background.ok_or("missing background")?,
+            platforms: platforms,
+            spawns: spawns,
+        };
+        if level.spawns.is_empty() {
+            level.spawns = (0..Self::DEFAULT_SPAWNS)
+                .map(|i| (i as f64 + 0.5) / Self::DEFAULT_SPAWNS as f64 - 0.5)
+                .filter_map(|x| level.ground_below(x, 0.5).map(|y| Vector2::new(x, y)))
+                .collect();
+        }
+        if level.spawns.is_empty() {
+            // Nothing to stand on, falling from the sky it is
+            level.spawns.push(Vector2::new(0., 0.4));
+        }
+        Ok(level)
+    }
+
+    pub fn is_on_ground(&self, feet: f64, left: f64, right: f64) -> bool {
+        self.platforms
+            .iter()
+            .any(|p| p.spans(left, right) && (p.top - feet).abs() < Self::EPSILON)
+    }
+
+    // Top of the highest platform crossed while falling from previous_feet to feet
+    pub fn landing(&self, previous_feet: f64, feet: f64, left: f64, right: f64) -> Option<f64> {
+        self.platforms
+            .iter()
+            .filter(|p| p.spans(left, right))
+            .filter(|p| previous_feet >= p.top - Self::EPSILON && feet < p.top)
+            .map(|p| p.top)
+            .fold(None, |highest, top| match highest {
+                Some(h) if h >= top => Some(h),
+                _ => Some(top),
+            })
+    }
+
+    // Bottom of the lowest solid platform hit while going up from previous_head to head
+    pub fn ceiling(&self, previous_head: f64, head: f64, left: f64, right: f64) -> Option<f64> {
+        self.platforms
+            .iter()
+            .filter(|p| p.is_solid() && p.spans(left, right))
+            .filter(|p| previous_head <= p.bottom + Self::EPSILON && head > p.bottom)
+            .map(|p| p.bottom)
+            .fold(None, |lowest, bottom| match lowest {
+                Some(l) if l <= bottom => Some(l),
+                _ => Some(bottom),
+            })
+    }
+
+    // True if the box runs into the side of a solid platform
+    pub fn blocks(&self, left: f64, right: f64, bottom: f64, top: f64) -> bool {
+        self.platforms.iter().any(|p| {
+            p.is_solid() && p.spans(left, right) && bottom < p.top - Self::EPSILON
+                && top > p.bottom + Self::EPSILON
+        })
+    }
+
+    // Top of the highest platform under the given point
+    pub fn ground_below(&self, x: f64, y: f64) -> Option<f64> {
+        self.platforms
+            .iter()
+            .filter(|p| p.left <= x && x < p.right && p.top <= y)
+            .map(|p| p.top)
+            .fold(None, |highest, top| match highest {
+                Some(h) if h >= top => Some(h),
+                _ => Some(top),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY: &'static str = "................................";
+    const GROUND: &'static str = "################################";
+
+    // The header, then empty rows over a solid ground
+    fn file(header: &str, rows: usize) -> String {
+        let mut lines = vec![header.to_string()];
+        lines.extend((1..rows).map(|_| EMPTY.to_string()));
+        lines.push(GROUND.to_string());
+        lines.join("\n")
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let header = "; a comment\n\n  ;another\nbackground = /bg10.png";
+        let level = Level::parse("test", &file(header, 20)).unwrap();
+        assert_eq!(level.background, "/bg10.png");
+        assert_eq!(level.platforms.len(), 1);
+        assert_eq!(level.platforms[0].kind, PlatformKind::Solid);
+    }
+
+    #[test]
+    fn comments_need_their_own_prefix() {
+        let error = Level::parse("test", &file("#comment\nbackground = /bg10.png", 20)).unwrap_err();
+        assert_eq!(error, "line 1: expected tiles or <setting> = <value>");
+        let error = Level::parse("test", &file("background = /bg10.png\n####", 20)).unwrap_err();
+        assert_eq!(error, "line 2: expected 32 tiles");
+    }
+
+    #[test]
+    fn rows_have_to_fill_the_arena() {
+        let error = Level::parse("test", &file("background = /bg10.png", 19)).unwrap_err();
+        assert_eq!(error, "expected 20 rows of tiles, found 19");
+        let error = Level::parse("test", &file("background = /bg10.png\n.......", 20)).unwrap_err();
+        assert_eq!(error, "line 2: expected 32 tiles");
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let error = Level::parse("test", &file("music = /jump.ogg", 20)).unwrap_err();
+        assert_eq!(error, "line 1: unknown setting music");
+        let error = Level::parse("test", &file("; no background", 20)).unwrap_err();
+        assert_eq!(error, "missing background");
+    }
+
+    #[test]
+    fn shipped_levels_load() {
+        let meadow = Level::parse("meadow", include_str!("../resources/levels/meadow.txt")).unwrap();
+        let ruins = Level::parse("ruins", include_str!("../resources/levels/ruins.txt")).unwrap();
+        assert_ne!(meadow.background, ruins.background);
+        assert!(ruins.platforms.iter().any(|p| p.kind == PlatformKind::OneWay));
+        assert!(!ruins.spawns.is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use rand::Rng;
+use bot::{Bot, Difficulty};
+use fixtures;
+use input::Input;
+use net::{Session, Setup, Socket};
+use rng::GameRng;
+use rollback::Rollback;
+use world::{Event, World};
+
+// Matches between bots, all in this process, through real sessions talking
+// over a fake network that delays, reorders and drops packets. Every peer has
+// to agree with a plain run of the inputs it confirmed, no window needed.
+pub struct Loopback {
+    pub seed: u64,
+    pub players: usize,
+    pub rollback: bool,
+    // One way, in ticks
+    pub latency: usize,
+    // Extra random latency, in ticks
+    pub jitter: usize,
+    // Chance of losing each packet, from 0 to 1
+    pub loss: f64,
+    pub ticks: usize,
+}
+
+// Where packets wait until they arrive, one tick of the loop at a time
+struct Network {
+    now: usize,
+    latency: usize,
+    jitter: usize,
+    loss: f64,
+    rng: GameRng,
+    // Arrival, sender, receiver and content
+    packets: Vec<(usize, SocketAddr, SocketAddr, Vec<u8>)>,
+}
+
+struct FakeSocket {
+    address: SocketAddr,
+    network: Rc<RefCell<Network>>,
+}
+
+impl Socket for FakeSocket {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let mut network = self.network.borrow_mut();
+        let network = &mut *network;
+        if network.rng.gen::<f64>() < network.loss {
+            return Ok(packet.len());
+        }
+        let jitter = network.rng.gen_range(0, network.jitter + 1);
+        let arrival = network.now + network.latency + jitter;
+        network.packets.push((arrival, self.address, address, packet.to_vec()));
+        Ok(packet.len())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut network = self.network.borrow_mut();
+        let now = network.now;
+        let address = self.address;
+        match network.packets.iter().position(|p| p.0 <= now && p.2 == address) {
+            Some(i) => {
+                let (_, from, _, packet) = network.packets.remove(i);
+                // Cut like a UDP packet too big for the buffer
+                let size = packet.len().min(buffer.len());
+                buffer[..size].copy_from_slice(&packet[..size]);
+                Ok((size, from))
+            }
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no packet")),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+}
+
+struct Peer {
+    net: Session,
+    world: World,
+    bot: Bot,
+    rollback: Option<Rollback>,
+    // Inputs of every tick that can no longer change, and the checksum of
+    // the world right after it
+    confirmed: Vec<Vec<Input>>,
+    checksums: Vec<u64>,
+}
+
+impl Peer {
+    // One frame of the game, like MainState::tick
+    fn play(&mut self, ticks: usize) {
+        let (slot, players, rollback) = match self.net.setup {
+            Some(ref setup) if self.net.is_ready() => (setup.slot, setup.players, setup.rollback),
+            _ => return,
+        };
+        if rollback {
+            let rollback = self.rollback.get_or_insert_with(|| Rollback::new(players));
+            let playing = rollback.tick < ticks;
+            self.net.set_tick(rollback.tick);
+            if playing {
+                self.net.add_local_input(self.bot.think(&self.world, slot));
+            }
+            rollback.pull(&self.net);
+            if playing && rollback.can_advance() && !self.net.is_blocked() {
+                rollback.advance(&mut self.world);
+            }
+            for confirmed in rollback.take_confirmed(&mut self.world) {
+                self.net.record_checksum(confirmed.checksum);
+                self.confirmed.push(confirmed.inputs);
+                self.checksums.push(confirmed.checksum);
+            }
+            return;
+        }
+        for _ in 0..self.net.ticks_to_run() {
+            if self.checksums.len() >= ticks {
+                return;
+            }
+            self.net.add_local_input(self.bot.think(&self.world, slot));
+            let inputs = match self.net.next_inputs() {
+                Some(inputs) => inputs,
+                None => return,
+            };
+            self.world.step(&inputs);
+            let checksum = self.world.checksum();
+            self.net.record_checksum(checksum);
+            self.confirmed.push(inputs);
+            self.checksums.push(checksum);
+        }
+    }
+}
+
+impl Loopback {
+    // Game ticks before calling it a deadlock, for each tick to play
+    const GIVE_UP: usize = 20;
+    // Clients say hello a few times a second, in real time
+    const LOBBY_FRAMES: usize = 5000;
+    // Ticks of inputs a session may hold, what is confirmed goes
+    const MAX_KEPT: usize = 120;
+
+    pub fn run(&self) -> Result<(), String> {
+        let network = Rc::new(RefCell::new(Network {
+            now: 0,
+            latency: self.latency,
+            jitter: self.jitter,
+            loss: self.loss,
+            rng: GameRng::new(self.seed ^ 0x5EED),
+            packets: vec![],
+        }));
+        let socket = |port: u16| {
+            Box::new(FakeSocket {
+                address: SocketAddr::from(([127, 0, 0, 1], port)),
+                network: network.clone(),
+            })
+        };
+        let setup = Setup {
+            slot: 0,
+            seed: self.seed,
+            players: self.players,
+            level: fixtures::level().name,
+            rules: fixtures::endless(),
+            rollback: self.rollback,
+        };
+        let host = SocketAddr::from(([127, 0, 0, 1], Session::DEFAULT_PORT));
+        let mut peers: Vec<Peer> = (0..self.players)
+            .map(|slot| Peer {
+                net: if slot == 0 {
+                    Session::host_on(socket(Session::DEFAULT_PORT), setup.clone())
+                } else {
+                    Session::connect_on(socket(Session::DEFAULT_PORT + slot as u16), host, false)
+                },
+                world: fixtures::world(self.seed, self.players),
+                bot: Bot::new(Difficulty::Hard),
+                rollback: None,
+                confirmed: vec![],
+                checksums: vec![],
+            })
+            .collect();
+
+        let mut frame = 0;
+        let mut started = None;
+        while peers.iter().any(|p| p.checksums.len() < self.ticks) {
+            match started {
+                Some(start) if frame - start > Self::GIVE_UP * self.ticks => {
+                    return Err(format!("stuck after {} ticks", frame - start));
+                }
+                None if peers.iter().all(|p| p.net.is_ready()) => started = Some(frame),
+                None if frame > Self::LOBBY_FRAMES => return Err("nobody joined".to_string()),
+                None => thread::sleep(Duration::from_millis(1)),
+                _ => (),
+            }
+            network.borrow_mut().now = frame;
+            for peer in &mut peers {
+                peer.net.receive();
+                peer.play(self.ticks);
+            }
+            for (slot, peer) in peers.iter_mut().enumerate() {
+                peer.net.send();
+                if peer.net.is_blocked() {
+                    return Err(peer.net.status().unwrap_or_default());
+                }
+                if peer.net.kept() > Self::MAX_KEPT {
+                    return Err(format!("P{} keeps {} ticks of inputs", slot + 1, peer.net.kept()));
+                }
+            }
+            frame += 1;
+        }
+
+        // A plain run of the confirmed inputs, no network or guess involved
+        let mut reference = fixtures::world(self.seed, self.players);
+        for tick in 0..self.ticks {
+            reference.step(&peers[0].confirmed[tick]);
+            for (slot, peer) in peers.iter().enumerate() {
+                if peer.confirmed[tick] != peers[0].confirmed[tick] {
+                    return Err(format!("P{} played other inputs at tick {}", slot + 1, tick));
+                }
+                if peer.checksums[tick] != reference.checksum() {
+                    return Err(format!("P{} differs from the reference at tick {}", slot + 1, tick));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn lockstep_over_a_lossy_network() {
+    let loopback = Loopback {
+        seed: 1,
+        players: 4,
+        rollback: false,
+        latency: 6,
+        jitter: 2,
+        loss: 0.1,
+        ticks: 600,
+    };
+    assert_eq!(loopback.run(), Ok(()));
+}
+
+#[test]
+fn rollback_matches_a_plain_run() {
+    let loopback = Loopback {
+        seed: 2,
+        players: 2,
+        rollback: true,
+        latency: 6,
+        jitter: 2,
+        loss: 0.05,
+        ticks: 1200,
+    };
+    assert_eq!(loopback.run(), Ok(()));
+}
+
+#[test]
+fn rollback_with_eight_players_and_heavy_loss() {
+    let loopback = Loopback {
+        seed: 3,
+        players: 8,
+        rollback: true,
+        latency: 3,
+        jitter: 3,
+        loss: 0.3,
+        ticks: 600,
+    };
+    assert_eq!(loopback.run(), Ok(()));
+}
+
+#[test]
+fn ticks_run_again_do_not_replay_their_events() {
+    let mut world = fixtures::world(4, 2);
+    let mut rollback = Rollback::new(2);
+    let jump = Input { x: 0, y: 127 };
+    let run = Input { x: 127, y: 0 };
+    let mut jumps = 0;
+    for tick in 0..30 {
+        // P1 jumps once, P2 stops running at tick 10 but it is only known
+        // at tick 14, after four ticks guessed wrong
+        rollback.add_input(tick, 0, if tick == 10 { jump } else { Input::default() });
+        if tick < 10 {
+            rollback.add_input(tick, 1, run);
+        } else if tick == 14 {
+            for late in 10..15 {
+                rollback.add_input(late, 1, Input::default());
+            }
+        } else if tick > 14 {
+            rollback.add_input(tick, 1, Input::default());
+        }
+        for event in rollback.advance(&mut world) {
+            if let Event::Jump(0) = event {
+                jumps += 1;
+            }
+        }
+    }
+    assert_eq!(rollback.rollbacks, 1);
+    assert_eq!(jumps, 1);
+}
@@ -2,22 +2,42 @@
 extern crate cgmath;
 extern crate ggez;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 use ggez::conf;
 use ggez::event;
 use ggez::{Context, GameResult};
 use ggez::graphics;
 use ggez::timer;
+use std::path::Path;
 use std::time::Duration;
 use cgmath::Vector2;
-use cgmath::MetricSpace;
 use controls::Controls;
-use display::Screen;
+use display::{Camera, Screen};
 use assets::Assets;
 use ui::Fps;
-use player::{Facing, Player, PlayerType};
-use particles::Blood;
+use decals::Decals;
+use texts::FontKind;
 use helpers::*;
-use bonus::{Bonus, BonusText};
+use world::{Event, World};
+use options::Options;
+use input::Input;
+use replay::{Playback, Replay};
+use player::Player;
+use bot::{Bot, Difficulty};
+use gamepad::{Controllers, Gamepad};
+use bonus::BonusDefinition;
+use particles::ParticleDefinition;
+use menu::{ControlsMenu, PauseAction, PauseMenu};
+use scene::{Scene, SceneStack};
+use level::Level;
+use rules::Rules;
+use net::{Session, Setup};
+use rollback::Rollback;
+use snapshot::{SaveState, Snapshot};
+use rand::Rng;
 
 mod controls;
 mod display;
@@ -25,290 +45,807 @@ mod assets;
 mod player;
 mod animation;
 mod particles;
+mod decals;
 mod helpers;
 mod ui;
 mod bonus;
+mod world;
+mod rng;
+mod options;
+mod input;
+mod replay;
+mod collision;
+mod bot;
+mod gamepad;
+mod config;
+mod menu;
+mod level;
+mod rules;
+mod scene;
+mod snapshot;
+mod textures;
+mod texts;
+mod net;
+mod rollback;
+#[cfg(test)]
+mod fixtures;
+#[cfg(test)]
+mod loopback;
 
-const GROUND_Y: f64 = -0.33;
 const GRAVITY_MAGIC_NUMBER: f64 = 20.;
 
-enum Scene {
-    Intro,
-    Credits,
-    Game,
-}
-
 // Main state
 struct MainState {
     screen: Screen,
     assets: Assets,
-    players: [Player; 2],
-    text_scores: [graphics::Text; 2],
-    blood_particles: Vec<Blood>,
+    world: World,
+    bonus_definitions: Vec<BonusDefinition>,
+    particle_definitions: Vec<ParticleDefinition>,
+    levels: Vec<Level>,
+    backgrounds: Vec<graphics::Image>,
+    level_texts: Vec<graphics::Text>,
+    level: usize,
+    rules: Rules,
+    rules_text: Vec<graphics::Text>,
+    seed: u64,
+    player_count: usize,
+    controls: Vec<Controls>,
+    controls_menu: ControlsMenu,
+    controls_changed: bool,
+    pause_menu: PauseMenu,
+    inputs: Vec<Vector2<f64>>,
+    slots: Vec<Option<Difficulty>>,
+    slot_texts: Vec<graphics::Text>,
+    bots: Vec<Option<Bot>>,
+    gamepads: Vec<Gamepad>,
+    controllers: Controllers,
+    // Shown for a while when a round ends
+    banner: Option<(f64, graphics::Text)>,
+    winner: Option<usize>,
+    results_texts: Vec<graphics::Text>,
     fps: Fps,
-    scene: Scene,
-    bonus_factory: bonus::Factory,
-    bonuses: Vec<Bonus>,
-    bonuses_text: Vec<BonusText>,
+    decals: Decals,
+    scenes: SceneStack,
+    accumulator: f64,
+    // The match being recorded, until it is over
+    recording: Option<Replay>,
+    // Where matches are recorded, and how many were saved there
+    record_path: Option<String>,
+    recorded: usize,
+    playback: Option<Playback>,
+    // Network game, this machine controls a single player
+    net: Option<Session>,
+    rollback: Option<Rollback>,
+    // The match as spectators see it, some time behind, and its next tick
+    broadcast: Option<(usize, World)>,
+    camera: Camera,
+    fullscreen: bool,
+    // Size of the window to go back to when leaving fullscreen
+    windowed_size: (u32, u32),
+    // Fullscreen was toggled, the window is dealt with on the next update
+    window_changed: bool,
+    // Matches start from there instead of the beginning
+    scenario: Option<Snapshot>,
+    replay_help: graphics::Text,
 }
 
 impl MainState {
-    const DESIRED_FPS: u64 = 60;
+    // Longest frame taken into account, so a hiccup doesn't trigger
+    // hundreds of simulation ticks at once
+    const MAX_FRAME_TIME: f64 = 0.25;
+    const BANNER_TIME: f64 = 2.;
+    // F5 saves there, F9 loads it
+    const QUICK_SAVE: &'static str = "quicksave.state";
 
-    fn new(ctx: &mut Context) -> GameResult<MainState> {
+    fn new(ctx: &mut Context, options: &Options) -> GameResult<MainState> {
         graphics::set_default_filter(ctx, graphics::FilterMode::Nearest);
-        let assets = Assets::new(ctx)?;
-        let text_scores = [
-            graphics::Text::new(ctx, "0", &assets.font)?,
-            graphics::Text::new(ctx, "0", &assets.font)?,
-        ];
+        let windowed_size = ctx.gfx_context.get_window().size();
+        if options.fullscreen {
+            set_fullscreen(ctx, true, windowed_size)?;
+        }
+        // Drawing is in window pixels, whatever the size
+        let (width, height) = ctx.gfx_context.get_window().drawable_size();
+        graphics::set_screen_coordinates(ctx, 0., width as f32, 0., height as f32)?;
+        let bonus_definitions = BonusDefinition::load_all(ctx)?;
+        let particle_definitions = ParticleDefinition::load_all(ctx)?;
+        let assets = Assets::new(ctx, &bonus_definitions, &particle_definitions)?;
         let fps = Fps {
-            text: graphics::Text::new(ctx, "FPS:", &assets.font)?,
+            value: 0,
             cooldown: 1.0,
         };
-        let player1 = Player::new(
-            Controls {
-                up: event::Keycode::Up,
-                left: event::Keycode::Left,
-                right: event::Keycode::Right,
-            },
-            PlayerType::Player1,
-            player1_animation(ctx)?,
-        );
-        let player2 = Player::new(
-            Controls {
-                up: event::Keycode::E,
-                left: event::Keycode::S,
-                right: event::Keycode::F,
+        let controls = match config::load_controls(ctx, Player::MAX_COUNT) {
+            Ok(controls) => controls,
+            Err(e) => {
+                println!("Could not load controls, using defaults: {}", e);
+                (0..Player::MAX_COUNT).map(Controls::defaults).collect()
+            }
+        };
+        let mut levels = vec![];
+        let mut backgrounds = vec![];
+        let mut level_texts = vec![];
+        for name in Level::list(ctx)? {
+            let level = Level::load(ctx, &name)?;
+            backgrounds.push(helpers::background(ctx, &level)?);
+            let text = format!("level: {} (L to change)", level.name);
+            level_texts.push(graphics::Text::new(ctx, &text, &assets.font_small)?);
+            levels.push(level);
+        }
+        let playback = match options.replay {
+            Some(ref path) => {
+                let replay = Replay::load(path)?;
+                if replay.players < Player::MIN_COUNT || replay.players > Player::MAX_COUNT {
+                    return Err(ggez::GameError::ResourceLoadError(format!(
+                        "replay is for {} players",
+                        replay.players
+                    )));
+                }
+                Some(Playback::new(replay))
+            }
+            None => None,
+        };
+        let state = match options.state {
+            Some(ref path) => {
+                let state = SaveState::load(path)?;
+                state.validate(bonus_definitions.len(), particle_definitions.len())?;
+                Some(state)
+            }
+            None => None,
+        };
+        let seed = match playback {
+            Some(ref p) => p.replay.seed,
+            None => options.seed,
+        };
+        let player_count = match (&playback, &state) {
+            (&Some(ref p), _) => p.replay.players,
+            (&None, &Some(ref s)) => s.snapshot.players.len(),
+            (&None, &None) => options.players,
+        };
+        let level_name = match (&playback, &state, &options.level) {
+            (&Some(ref p), _, _) => Some(p.replay.level.clone()),
+            (&None, &Some(ref s), _) => Some(s.level.clone()),
+            (&None, &None, &Some(ref name)) => Some(name.clone()),
+            (&None, &None, &None) => None,
+        };
+        let level = match level_name {
+            Some(name) => match levels.iter().position(|l| l.name == name) {
+                Some(level) => level,
+                None => {
+                    return Err(ggez::GameError::ResourceLoadError(format!("unknown level {}", name)))
+                }
             },
-            PlayerType::Player2,
-            player2_animation(ctx)?,
-        );
-        let s = MainState {
+            None => 0,
+        };
+        let rules = match (&playback, &state) {
+            (&Some(ref p), _) => p.replay.rules,
+            (&None, &Some(ref s)) => s.rules,
+            (&None, &None) => options.rules,
+        };
+        let recording = match options.record {
+            Some(_) => Some(Replay::new(seed, player_count, &levels[level].name, rules)),
+            None => None,
+        };
+        let net = match (options.host, &options.join, &options.watch) {
+            (Some(port), _, _) => {
+                let setup = Setup {
+                    slot: 0,
+                    seed: seed,
+                    players: player_count,
+                    level: levels[level].name.clone(),
+                    rules: rules,
+                    rollback: options.rollback,
+                };
+                let mut session = Session::host(port, setup)?;
+                session.broadcast_delay = options.broadcast_delay as f64;
+                Some(session)
+            }
+            (None, &Some(ref address), _) => Some(Session::join(address)?),
+            (None, &None, &Some(ref address)) => Some(Session::watch(address)?),
+            (None, &None, &None) => None,
+        };
+        let scenes = match (&playback, &state, &net) {
+            (&Some(_), _, _) | (_, &Some(_), _) => vec![Scene::Intro, Scene::Game],
+            (&None, &None, &Some(_)) => vec![Scene::Intro, Scene::Lobby],
+            (&None, &None, &None) => vec![Scene::Intro],
+        };
+        let replay_help = graphics::Text::new(
+            ctx,
+            "REPLAY  space: pause  right: step  F: fast forward",
+            &assets.font_small,
+        )?;
+        let mut s = MainState {
             assets: assets,
-            text_scores: text_scores,
-            screen: Screen::new(),
-            players: [player1, player2],
-            blood_particles: vec![],
+            banner: None,
+            winner: None,
+            results_texts: vec![],
+            screen: Screen::new(width, height, options.integer_scaling),
+            fullscreen: options.fullscreen,
+            windowed_size: windowed_size,
+            window_changed: false,
+            world: World::new(
+                seed,
+                player_count,
+                levels[level].clone(),
+                rules,
+                bonus_definitions.clone(),
+                particle_definitions.clone(),
+            ),
+            bonus_definitions: bonus_definitions,
+            particle_definitions: particle_definitions,
+            levels: levels,
+            backgrounds: backgrounds,
+            level_texts: level_texts,
+            level: level,
+            rules: rules,
+            rules_text: vec![],
+            seed: seed,
+            player_count: player_count,
+            controls: controls,
+            controls_menu: ControlsMenu::new(),
+            controls_changed: false,
+            pause_menu: PauseMenu::new(),
+            inputs: vec![Vector2::new(0., 0.); player_count],
+            slots: vec![None; Player::MAX_COUNT],
+            slot_texts: vec![],
+            bots: vec![],
+            gamepads: vec![],
+            controllers: Controllers::new(),
             fps: fps,
-            scene: Scene::Intro,
-            bonus_factory: bonus::Factory::new(ctx)?,
-            bonuses: vec![],
-            bonuses_text: vec![],
+            decals: Decals::new(),
+            scenes: SceneStack::new(scenes),
+            accumulator: 0.,
+            recording: recording,
+            record_path: options.record.clone(),
+            recorded: 0,
+            playback: playback,
+            net: net,
+            rollback: None,
+            broadcast: None,
+            camera: Camera::new(),
+            scenario: state.map(|s| s.snapshot),
+            replay_help: replay_help,
         };
+        if let Some(ref snapshot) = s.scenario {
+            s.world.restore(snapshot);
+        }
         Ok(s)
     }
-}
 
-impl event::EventHandler for MainState {
-    fn update(&mut self, ctx: &mut Context, dt: Duration) -> GameResult<()> {
-        if !timer::check_update_time(ctx, Self::DESIRED_FPS) {
-            return Ok(());
+    // Sets up a new match, score texts are rebuilt on the next update
+    fn start_match(&mut self) {
+        self.decals.clear();
+        self.world = World::new(
+            self.seed,
+            self.player_count,
+            self.levels[self.level].clone(),
+            self.rules,
+            self.bonus_definitions.clone(),
+            self.particle_definitions.clone(),
+        );
+        if let Some(ref snapshot) = self.scenario {
+            self.world.restore(snapshot);
         }
-        let seconds = 1.0 / (Self::DESIRED_FPS as f64);
+        self.inputs = vec![Vector2::new(0., 0.); self.player_count];
+        self.bots = self.slots[..self.player_count]
+            .iter()
+            .map(|slot| slot.map(Bot::new))
+            .collect();
+        self.banner = None;
+        self.camera = Camera::new();
+        self.rollback = match self.net {
+            Some(ref net) if net.is_rollback() => Some(Rollback::new(self.player_count)),
+            _ => None,
+        };
+        // A match left halfway is kept too
+        self.finish_recording();
+        if self.record_path.is_some() {
+            let level = &self.levels[self.level].name;
+            self.recording = Some(Replay::new(self.seed, self.player_count, level, self.rules));
+        }
+        if let Some(ref mut playback) = self.playback {
+            playback.restart();
+        }
+        self.accumulator = 0.;
+        self.scenes.reset(vec![Scene::Intro, Scene::Game]);
+    }
 
-        match self.scene {
-            // Game Scene
-            Scene::Game => {
-                // Update players
-                for player in &mut self.players {
-                    player.update_mutations(dt);
-                    player.update_position(&self.screen, seconds, &self.assets)?;
-                }
-
-                // Particles
-                {
-                    // Move
-                    for blood_particle in &mut self.blood_particles {
-                        blood_particle.velocity.y -= seconds * 1.2 / GRAVITY_MAGIC_NUMBER;
-                        blood_particle.position.x += blood_particle.velocity.x;
-                        blood_particle.position.y += blood_particle.velocity.y;
-                    }
-                    // Remove if out of screen
-                    self.blood_particles
-                        .retain(|blood_particle| blood_particle.position.y > -0.5);
-                }
+    // Network games use the settings of the host, whatever was picked here
+    fn join_match(&mut self) {
+        let setup = match self.net {
+            Some(ref net) if net.is_ready() => net.setup.clone().unwrap(),
+            _ => return,
+        };
+        self.level = match self.levels.iter().position(|l| l.name == setup.level) {
+            Some(level) => level,
+            None => {
+                println!("Cannot join, unknown level {}", setup.level);
+                return self.back_to_menu();
+            }
+        };
+        self.seed = setup.seed;
+        self.player_count = setup.players;
+        self.rules = setup.rules;
+        self.rules_text = vec![];
+        self.start_match();
+        // Spectators come in the middle of the match
+        if let Some(snapshot) = self.net.as_mut().and_then(|net| net.take_state()) {
+            self.world.restore(&snapshot);
+        }
+    }
 
-                // Bonus factory
-                self.bonus_factory.update(seconds);
+    // Same players and rules, the seed follows from the previous match so
+    // that it can still be replayed
+    fn rematch(&mut self) {
+        // The others would have to agree on it
+        if self.net.is_some() {
+            return;
+        }
+        if self.playback.is_none() {
+            self.seed = self.world.rng.next_u64();
+            println!("Seed: {}", self.seed);
+        }
+        self.start_match();
+    }
 
-                // Bonus text
-                {
-                    // Move
-                    for bonus_text in &mut self.bonuses_text {
-                        bonus_text.position.y += seconds * 0.1;
-                        bonus_text.cooldown -= seconds;
-                    }
-                    // Remove if out of screen
-                    self.bonuses_text
-                        .retain(|bonus_text| bonus_text.cooldown > 0.);
-                }
-
-                // Bonus
-                {
-                    // Spawn
-                    match self.bonus_factory.spawn(ctx, dt)? {
-                        Some(bonuses) => {
-                            for bonus in bonuses {
-                                self.bonuses.push(bonus);
-                            }
-                            
-                        }
-                        None => ()
-                    }
-                    // Move
-                    for bonus in &mut self.bonuses {
-                        bonus.rotation += (seconds * 500. * bonus.velocity.x) as f32;
-                        if bonus.position.y > GROUND_Y - 0.03 {
-                            bonus.velocity.y -= seconds / GRAVITY_MAGIC_NUMBER;
-                        } else {
-                            bonus.velocity.y *= -0.9;
-                            if bonus.velocity.y < 0.01 {
-                                bonus.velocity.x = 0.;
-                            }
-                        }
-                        if bonus.position.x > 0.5 || bonus.position.x < -0.5 {
-                            bonus.position.x *= -1.;
-                        }
-                        bonus.position.y += bonus.velocity.y;
-                        bonus.position.x += bonus.velocity.x;
-                    }
+    fn back_to_menu(&mut self) {
+        self.playback = None;
+        self.net = None;
+        self.rollback = None;
+        self.broadcast = None;
+        self.scenario = None;
+        self.decals.clear();
+        self.slot_texts = vec![];
+        self.scenes.reset(vec![Scene::Intro]);
+    }
+
+    fn pause(&mut self) {
+        // Everybody else would be frozen too
+        if self.net.is_some() {
+            return;
+        }
+        self.pause_menu = PauseMenu::new();
+        self.scenes.push(Scene::Pause);
+    }
+
+    fn pause_action(&mut self, action: PauseAction) {
+        match action {
+            PauseAction::Resume => self.scenes.pop(),
+            PauseAction::Restart => self.start_match(),
+            PauseAction::Settings => {
+                self.controls_menu = ControlsMenu::new();
+                self.scenes.push(Scene::Controls);
+            }
+            PauseAction::Quit => self.back_to_menu(),
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let events = if self.rollback.is_some() { self.rollback_tick() } else { self.lockstep_tick() };
+        let events = match events {
+            Some(events) => events,
+            None => return Ok(()),
+        };
+        self.decals.update(World::TICK);
+        for event in events {
+            match event {
+                Event::Jump(i) => self.assets.jump[i].play()?,
+                Event::Kill { .. } => self.assets.death.play()?,
+                Event::BonusTaken(_) | Event::ShieldBroken(_) => self.assets.take_bonus.play()?,
+                // Scores are drawn straight from the world
+                Event::ScoreChanged(_) => (),
+                Event::Stain(stain) => self.decals.add(stain),
+                Event::RoundOver { winner } => {
+                    let text = match winner {
+                        Some(i) => format!("P{} wins round {}", i + 1, self.world.round),
+                        None => format!("round {} is a draw", self.world.round),
+                    };
+                    let text = graphics::Text::new(ctx, &text, &self.assets.font)?;
+                    self.banner = Some((Self::BANNER_TIME, text));
+                    self.decals.clear();
+                }
+                // A guess may end the match, only settled ticks are trusted
+                Event::MatchOver { .. } if self.rollback.is_some() => (),
+                Event::MatchOver { winner } => {
+                    self.finish_recording();
+                    self.show_results(winner);
                 }
+            }
+        }
+        let settled = self.rollback.as_ref().map_or(false, |r| r.is_settled());
+        if settled && self.world.over && self.scenes.top() == Scene::Game {
+            self.finish_recording();
+            let winner = rules::leader(self.world.stats.iter().map(|s| s.rounds_won));
+            self.show_results(winner);
+        }
+        Ok(())
+    }
 
-                // Collision
-                {
-                    for i in 0..self.players.len() {
-                        // Update cbox_size with bonuses
-                        let mut cbox_size = self.players[i].cbox_size;
-                        for m in &mut self.players[i].mutations {
-                            cbox_size.x *= m.size_factor;
-                            cbox_size.y *= m.size_factor;
-                        }
-
-                        // With other players
-                        let n = i + 1;
-                        for j in n..self.players.len() {
-                            let distance =
-                                self.players[i].position.distance(self.players[j].position);
-                            if distance < cbox_size.x {
-                                // This part should be updated.
-                                // It seems cbox_size.y is never used nowhere.
-                                // It must be used for better collisions.
-                                let pos_y_i = self.players[i].previous_position.y;
-                                let pos_y_j = self.players[j].previous_position.y;
-                                let (frag, killer, victim) =
-                                    if pos_y_i > pos_y_j && self.players[i].velocity.y < 0. {
-                                        (true, Some(i), Some(j))
-                                    } else if pos_y_j > pos_y_i && self.players[j].velocity.y < 0. {
-                                        (true, Some(j), Some(i))
-                                    } else {
-                                        (false, None, None)
-                                    };
-                                if frag {
-                                    let (killer, victim) = (killer.unwrap(), victim.unwrap());
-                                    for _ in 0..7 {
-                                        self.blood_particles.push(
-                                            random_blood_particle(self.players[victim].position),
-                                        );
-                                    }
-                                    kill(&mut self.players, killer, victim);
-                                    self.text_scores[killer] = score_text(
-                                        ctx,
-                                        self.players[killer].score,
-                                        &mut self.assets,
-                                    )?;
-                                    self.assets.death.play()?;
-                                }
-                            }
-                        }
-                        // With bonuses
-                        for j in 0..self.bonuses.len() {
-                            let bonus = &mut self.bonuses[j];
-                            let distance = self.players[i].position.distance(bonus.position);
-                            bonus.has_collision = distance <= cbox_size.x;
-                            if bonus.has_collision {
-                                self.assets.take_bonus.play()?;
-                                self.bonuses_text.push(BonusText { 
-                                    text: graphics::Text::new(ctx, &bonus.description, &self.assets.font_small)?,
-                                    position: bonus.position,
-                                    cooldown: 1.0,
-                                });
-                                match bonus.apply(&mut self.players[i]) {
-                                    Some(m) => self.players[i].mutations.push(m),
-                                    None => {
-                                        self.text_scores[i] = score_text(
-                                            ctx,
-                                            self.players[i].score,
-                                            &mut self.assets,
-                                        )?;
-                                    }
-                                }
-                            }
-                        }
-                        self.bonuses.retain(|bonus| !bonus.has_collision);
-                    }
+    fn show_results(&mut self, winner: Option<usize>) {
+        self.winner = winner;
+        self.results_texts = vec![];
+        self.scenes.replace(Scene::Results);
+    }
+
+    // Runs the next tick on guesses, going back over the past ones when they
+    // were wrong. None while too far ahead of the others.
+    fn rollback_tick(&mut self) -> Option<Vec<Event>> {
+        let input = Input::from_axis(human_axis(self.inputs[0], &self.gamepads, None));
+        let (net, rollback) = match (self.net.as_mut(), self.rollback.as_mut()) {
+            (Some(net), Some(rollback)) => (net, rollback),
+            _ => return None,
+        };
+        net.set_tick(rollback.tick);
+        net.add_local_input(input);
+        rollback.pull(net);
+        let stalled = !rollback.can_advance() || net.is_blocked();
+        net.set_stalled(stalled);
+        if stalled {
+            return None;
+        }
+        let events = rollback.advance(&mut self.world);
+        for confirmed in rollback.take_confirmed(&mut self.world) {
+            if let (false, Some(recording)) = (confirmed.over, self.recording.as_mut()) {
+                recording.record(&confirmed.inputs);
+            }
+            net.record_checksum(confirmed.checksum);
+        }
+        Some(events)
+    }
+
+    // Runs the next tick once the inputs of every player are known, None
+    // while waiting for them
+    fn lockstep_tick(&mut self) -> Option<Vec<Event>> {
+        let inputs = match (self.playback.as_mut(), self.net.as_mut()) {
+            (Some(playback), _) => match playback.next_inputs() {
+                Some(inputs) => inputs,
+                None => return None,
+            },
+            // The first keyboard controls and every controller drive the local llama
+            (None, Some(net)) => {
+                net.add_local_input(Input::from_axis(human_axis(self.inputs[0], &self.gamepads, None)));
+                match net.next_inputs() {
+                    Some(inputs) => inputs,
+                    None => return None,
                 }
             }
-            _ => {}
+            (None, None) => {
+                let world = &self.world;
+                let gamepads = &self.gamepads;
+                self.inputs
+                    .iter()
+                    .zip(self.bots.iter_mut())
+                    .enumerate()
+                    .map(|(i, (axis, bot))| match *bot {
+                        Some(ref mut bot) => bot.think(world, i),
+                        None => Input::from_axis(human_axis(*axis, gamepads, Some(i))),
+                    })
+                    .collect()
+            }
+        };
+        // Nothing happens past the end, it is not worth keeping
+        if let (false, Some(recording)) = (self.world.over, self.recording.as_mut()) {
+            recording.record(&inputs);
         }
+        let events = self.world.step(&inputs);
+        if let Some(ref mut net) = self.net {
+            net.record_checksum(self.world.checksum());
+        }
+        Some(events)
+    }
 
-        self.fps.update(ctx, &self.assets, dt)?;
+    // Controllers get the first free human slot the first time they are
+    // used, so they can be plugged in at any time
+    fn gamepad(&mut self, id: i32) -> Option<&mut Gamepad> {
+        if !self.gamepads.iter().any(|g| g.id == id) {
+            let free_slot = {
+                let gamepads = &self.gamepads;
+                let slots = &self.slots;
+                (0..self.player_count)
+                    .find(|&slot| slots[slot].is_none() && !gamepads.iter().any(|g| g.slot == slot))
+            };
+            let slot = match (free_slot, self.scenes.top()) {
+                // Online, this machine only has the local llama
+                _ if self.net.is_some() => 0,
+                (Some(slot), _) => slot,
+                // One more player while still in the menu
+                (None, Scene::Intro) if self.player_count < Player::MAX_COUNT => {
+                    self.player_count += 1;
+                    self.slots[self.player_count - 1] = None;
+                    self.slot_texts = vec![];
+                    self.player_count - 1
+                }
+                _ => return None,
+            };
+            self.gamepads.push(Gamepad::new(id, slot));
+        }
+        self.gamepads.iter_mut().find(|g| g.id == id)
+    }
+
+    // Human, then bots from easy to hard
+    fn switch_slot(&mut self, slot: usize) {
+        self.slots[slot] = match self.slots[slot] {
+            None => Some(Difficulty::Easy),
+            Some(Difficulty::Easy) => Some(Difficulty::Normal),
+            Some(Difficulty::Normal) => Some(Difficulty::Hard),
+            Some(Difficulty::Hard) => None,
+        };
+        self.slot_texts = vec![];
+    }
+
+    // How far we are between the last tick and the next one
+    fn alpha(&self) -> f64 {
+        match self.playback {
+            Some(ref playback) if playback.paused || playback.is_finished() => 1.,
+            _ => self.accumulator / World::TICK,
+        }
+    }
 
+    fn quick_save(&self) {
+        let state = SaveState {
+            level: self.world.level.name.clone(),
+            rules: self.world.rules,
+            snapshot: self.world.snapshot(),
+        };
+        match state.save(Self::QUICK_SAVE) {
+            Ok(()) => println!("State saved to {}", Self::QUICK_SAVE),
+            Err(e) => println!("Could not save state: {}", e),
+        }
+    }
+
+    // Starts over from the quick save, with its level, rules and players
+    fn quick_load(&mut self) {
+        // Inputs from then on would not make sense from the seed
+        if self.net.is_some() || self.playback.is_some() || self.record_path.is_some() {
+            return println!("States cannot be loaded while recording, replaying or online");
+        }
+        let state = match SaveState::load(Self::QUICK_SAVE)
+            .and_then(|s| s.validate(self.bonus_definitions.len(), self.particle_definitions.len()).map(|_| s))
+        {
+            Ok(state) => state,
+            Err(e) => return println!("Could not load state: {}", e),
+        };
+        self.level = match self.levels.iter().position(|l| l.name == state.level) {
+            Some(level) => level,
+            None => return println!("Could not load state: unknown level {}", state.level),
+        };
+        self.player_count = state.snapshot.players.len();
+        self.rules = state.rules;
+        self.rules_text = vec![];
+        self.scenario = Some(state.snapshot);
+        self.start_match();
+    }
+
+    fn resize(&mut self, ctx: &mut Context, width: u32, height: u32) -> GameResult<()> {
+        graphics::set_screen_coordinates(ctx, 0., width as f32, 0., height as f32)?;
+        self.screen.resize(width, height);
         Ok(())
     }
 
-    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
-        graphics::clear(ctx);
-        draw_full_screen(ctx, &self.assets.bg, &self.screen)?;
+    // The host plays the match again for spectators, as far as they may see.
+    // It starts when the first one comes, from the last confirmed world.
+    fn update_broadcast(&mut self) {
+        let wanted = self.net.as_ref().map_or(false, |net| net.wants_state());
+        if let (true, None, Some(net)) = (wanted, self.broadcast.as_ref(), self.net.as_ref()) {
+            let (tick, snapshot) = match self.rollback {
+                Some(ref rollback) => rollback.confirmed_state(&self.world),
+                None => (net.confirmed(), self.world.snapshot()),
+            };
+            let mut world = World::new(
+                self.seed,
+                self.world.players.len(),
+                self.world.level.clone(),
+                self.world.rules,
+                self.world.bonus_definitions.clone(),
+                self.world.particle_definitions.clone(),
+            );
+            world.restore(&snapshot);
+            self.broadcast = Some((tick, world));
+        }
+        let (net, broadcast) = match (self.net.as_mut(), self.broadcast.as_mut()) {
+            (Some(net), Some(broadcast)) => (net, broadcast),
+            _ => return,
+        };
+        let (ref mut tick, ref mut world) = *broadcast;
+        while *tick < net.broadcast_tick() {
+            let inputs: Vec<Input> = (0..world.players.len())
+                .map(|slot| net.input(*tick, slot).unwrap_or_default())
+                .collect();
+            world.step(&inputs);
+            *tick += 1;
+        }
+        // Not before the delay is over, spectators see nothing newer
+        if net.wants_state() && *tick == net.broadcast_tick() {
+            net.set_state(*tick, &world.snapshot());
+        }
+    }
+
+    fn is_spectating(&self) -> bool {
+        self.net.as_ref().map_or(false, |net| net.is_spectator())
+    }
+
+    // The followed llama, or where the camera was left
+    fn camera_center(&self) -> Vector2<f64> {
+        match self.camera.follow.and_then(|i| self.world.players.get(i)) {
+            Some(player) => player.interpolated_position(self.alpha()),
+            None => self.camera.center,
+        }
+    }
+
+    // The screen for the world, the HUD stays on self.screen
+    fn view(&self) -> Screen {
+        self.screen.view(self.camera_center(), self.camera.zoom)
+    }
+
+    // Arrows move, +/- zoom, 1-8 follow a llama, 0 shows the whole arena
+    fn move_camera(&mut self, keycode: event::Keycode) {
+        const FOLLOW_KEYS: [event::Keycode; 8] = [
+            event::Keycode::Num1,
+            event::Keycode::Num2,
+            event::Keycode::Num3,
+            event::Keycode::Num4,
+            event::Keycode::Num5,
+            event::Keycode::Num6,
+            event::Keycode::Num7,
+            event::Keycode::Num8,
+        ];
+        let from = self.camera_center();
+        match keycode {
+            event::Keycode::Left => self.camera.pan(from, Vector2::new(-1., 0.)),
+            event::Keycode::Right => self.camera.pan(from, Vector2::new(1., 0.)),
+            event::Keycode::Up => self.camera.pan(from, Vector2::new(0., 1.)),
+            event::Keycode::Down => self.camera.pan(from, Vector2::new(0., -1.)),
+            event::Keycode::Equals | event::Keycode::KpPlus | event::Keycode::PageUp => self.camera.zoom(1),
+            event::Keycode::Minus | event::Keycode::KpMinus | event::Keycode::PageDown => self.camera.zoom(-1),
+            event::Keycode::Num0 | event::Keycode::Home => self.camera = Camera::new(),
+            _ => if let Some(i) = FOLLOW_KEYS.iter().position(|&k| k == keycode) {
+                if i < self.world.players.len() {
+                    self.camera.follow = Some(i);
+                }
+            },
+        }
+    }
 
-        match self.scene {
+    // Saves the match recorded so far, the first one to the path given and
+    // the next ones numbered after it
+    fn finish_recording(&mut self) {
+        let (path, recording) = match (self.record_path.as_ref(), self.recording.take()) {
+            (Some(path), Some(recording)) => (path, recording),
+            _ => return,
+        };
+        if recording.ticks.is_empty() {
+            return;
+        }
+        self.recorded += 1;
+        let path = numbered_path(path, self.recorded);
+        match recording.save(&path) {
+            Ok(()) => println!("Replay saved to {}", path),
+            Err(e) => println!("Could not save replay: {}", e),
+        }
+    }
+
+    fn draw_scene(&mut self, ctx: &mut Context, scene: Scene) -> GameResult<()> {
+        match scene {
             // Game Scene
             Scene::Game => {
+                let alpha = self.alpha();
+                let view = self.view();
+                draw_platforms(ctx, &self.world.level, &view)?;
 
                 // Images
-                for i in 0..self.players.len() {
-                    // Shadow
-                    let pos = Vector2::new(self.players[i].position.x, GROUND_Y - 0.08);
-                    let dest = helpers::point_from_position(pos, &self.screen);
-                    let size = self.screen.size_to_pixel(Vector2::new(0.1, 0.1 / 3.0));
-                    let draw_param = graphics::DrawParam {
-                        dest: dest,
-                        scale: graphics::Point {
-                            x: size.x as f32 / self.assets.shadow.width() as f32,
-                            y: size.y as f32 / self.assets.shadow.height() as f32,
-                        },
-                        ..Default::default()
+                for i in 0..self.world.players.len() {
+                    // Shadow, on whatever the llama would land on
+                    let position = self.world.players[i].interpolated_position(alpha);
+                    let feet = position.y - Player::FEET;
+                    let ground = if self.world.players[i].is_alive() {
+                        self.world.level.ground_below(position.x, feet + 0.01)
+                    } else {
+                        None
                     };
-                    graphics::set_color(ctx, graphics::Color::new(255., 255., 255., 0.5))?;
-                    graphics::draw_ex(ctx, &self.assets.shadow, draw_param)?;
-                    graphics::set_color(ctx, (255, 255, 255).into())?;
+                    if let Some(ground) = ground {
+                        let pos = Vector2::new(position.x, ground - 0.01);
+                        let dest = helpers::point_from_position(pos, &view);
+                        let size = view.size_to_pixel(Vector2::new(0.1, 0.1 / 3.0));
+                        let draw_param = graphics::DrawParam {
+                            dest: dest,
+                            scale: graphics::Point {
+                                x: size.x as f32 / self.assets.shadow.width() as f32,
+                                y: size.y as f32 / self.assets.shadow.height() as f32,
+                            },
+                            ..Default::default()
+                        };
+                        graphics::set_color(ctx, graphics::Color::new(255., 255., 255., 0.5))?;
+                        graphics::draw_ex(ctx, &self.assets.shadow, draw_param)?;
+                        graphics::set_color(ctx, (255, 255, 255).into())?;
+                    }
                     // End shadow
-                    self.players[i].draw(ctx, &self.screen)?;
+                    self.world.players[i].draw(ctx, &view, &self.assets, alpha)?;
                 }
-                for i in 0..self.bonuses.len() {
-                    self.bonuses[i].draw(ctx, &self.screen)?;
+                for i in 0..self.world.bonuses.len() {
+                    self.world.bonuses[i].draw(ctx, &view, &self.assets, alpha)?;
                 }
-                self.bonus_factory.draw(ctx, &self.screen)?;
-                for i in 0..self.blood_particles.len() {
-                    draw_blood(
-                        ctx,
-                        &mut self.blood_particles[i],
-                        &self.screen,
-                        &mut self.assets,
-                    )?;
+                self.world.bonus_factory.draw(ctx, &view, &self.assets, alpha)?;
+                for particle in &self.world.particles {
+                    let definition = &self.world.particle_definitions[particle.tag];
+                    particle.draw(ctx, definition, &view, &self.assets, alpha)?;
                 }
 
                 // Texts
-                quick_draw(ctx, &self.text_scores[0], (0.4, 0.45), &self.screen)?;
-                quick_draw(ctx, &self.text_scores[1], (-0.4, 0.45), &self.screen)?;
-                quick_draw(ctx, &self.fps.text, (0., -0.47), &self.screen)?;
-                for i in 0..self.bonuses_text.len() {
-                    let a = (self.bonuses_text[i].position.x, self.bonuses_text[i].position.y);
-                    quick_draw(ctx, &self.bonuses_text[i].text, a, &self.screen)?;
+                let count = self.world.players.len();
+                for (i, player) in self.world.players.iter().enumerate() {
+                    // One HUD slot per player, right to left like the spawns
+                    let x = 0.4 - 0.8 * i as f64 / (count - 1) as f64;
+                    let center = point_from_position(Vector2::new(x, 0.45), &self.screen);
+                    graphics::set_color(ctx, player.color())?;
+                    let scale = self.screen.pixel_scale() as f32;
+                    self.assets.texts.draw_number(ctx, FontKind::Regular, "", player.score, center, scale)?;
+                    draw_mutation_timers(ctx, player, Vector2::new(x, 0.43), &self.screen)?;
+                }
+                graphics::set_color(ctx, (255, 255, 255).into())?;
+                let center = point_from_position(Vector2::new(0., -0.47), &self.screen);
+                let scale = self.screen.pixel_scale() as f32;
+                self.assets.texts.draw_number(ctx, FontKind::Small, "FPS: ", self.fps.value, center, scale)?;
+                for bonus_text in &self.world.bonuses_text {
+                    let a = (bonus_text.position.x, bonus_text.position.y);
+                    let description = &self.world.bonus_definitions[bonus_text.tag].text;
+                    let text = self.assets.texts.get(ctx, FontKind::Small, description)?;
+                    quick_draw(ctx, text, a, &view)?;
+                }
+                let label = match_label(&self.world);
+                let text = self.assets.texts.get(ctx, FontKind::Small, &label)?;
+                quick_draw(ctx, text, (0., 0.35), &self.screen)?;
+                if let Some((_, ref text)) = self.banner {
+                    quick_draw(ctx, text, (0., 0.1), &self.screen)?;
+                }
+                if self.playback.is_some() {
+                    quick_draw(ctx, &self.replay_help, (0., 0.47), &self.screen)?;
+                }
+                if self.is_spectating() {
+                    let help = "WATCHING  arrows: move  +/-: zoom  1-8: follow  0: whole arena";
+                    let text = self.assets.texts.get(ctx, FontKind::Small, help)?;
+                    quick_draw(ctx, text, (0., 0.47), &self.screen)?;
+                }
+                if let Some(status) = self.net.as_ref().and_then(|net| net.status()) {
+                    let text = self.assets.texts.get(ctx, FontKind::Small, &status)?;
+                    quick_draw(ctx, text, (0., 0.2), &self.screen)?;
+                }
+            }
+            Scene::Lobby => {
+                transparent_layer(ctx, &self.screen, 0.75)?;
+                quick_draw(ctx, &self.assets.title, (0., 0.4), &self.screen)?;
+                let status = self.net
+                    .as_ref()
+                    .and_then(|net| net.status())
+                    .unwrap_or("starting...".to_string());
+                let text = self.assets.texts.get(ctx, FontKind::Small, &status)?;
+                quick_draw(ctx, text, (0., 0.), &self.screen)?;
+                let text = self.assets.texts.get(ctx, FontKind::Small, "BACKSPACE (or BACK): back to menu")?;
+                quick_draw(ctx, text, (0., -0.1), &self.screen)?;
+            }
+            Scene::Pause => {
+                transparent_layer(ctx, &self.screen, 0.75)?;
+                for (i, text) in self.pause_menu.texts.iter().enumerate() {
+                    quick_draw(ctx, text, (0., 0.2 + i as f64 / -12.), &self.screen)?;
+                }
+            }
+            Scene::Results => {
+                transparent_layer(ctx, &self.screen, 0.75)?;
+                for (i, text) in self.results_texts.iter().enumerate() {
+                    // Player lines in their color, between the title and the help
+                    let player = if i > 0 { self.world.players.get(i - 1) } else { None };
+                    if let Some(player) = player {
+                        graphics::set_color(ctx, player.color())?;
+                    }
+                    quick_draw(ctx, text, (0., 0.3 + i as f64 / -14.), &self.screen)?;
+                    graphics::set_color(ctx, (255, 255, 255).into())?;
+                }
+            }
+            Scene::Controls => {
+                transparent_layer(ctx, &self.screen, 0.75)?;
+                for (i, text) in self.controls_menu.texts.iter().enumerate() {
+                    quick_draw(ctx, text, (0., 0.3 + i as f64 / -10.), &self.screen)?;
                 }
             }
             Scene::Credits => {
-                transparent_layer(ctx, &self.screen)?;
+                transparent_layer(ctx, &self.screen, 0.75)?;
                 for i in 0..self.assets.credits.len() {
                     quick_draw(ctx, &self.assets.credits[i], (0., 0.3 + i as f64 / -15.), &self.screen)?;
                 }
@@ -316,75 +853,419 @@ impl event::EventHandler for MainState {
 
             // Intro Scene
             Scene::Intro => {
-                transparent_layer(ctx, &self.screen)?;
+                transparent_layer(ctx, &self.screen, 0.75)?;
 
-                for i in 0..self.assets.instructions_p1.len() {
-                    quick_draw(ctx, &self.assets.instructions_p1[i], (-0.15, 0.1 + i as f64 / -15.), &self.screen)?;
-                    quick_draw(ctx, &self.assets.instructions_p2[i], (0.15, 0.1 + i as f64 / -15.), &self.screen)?;
-                }
                 quick_draw(ctx, &self.assets.title, (0., 0.4), &self.screen)?;
                 quick_draw(ctx, &self.assets.authors,(0., 0.3), &self.screen)?;
-                quick_draw(ctx, &self.assets.single, (0., -0.4), &self.screen)?;
+                let player_count = &self.assets.player_counts[self.player_count - Player::MIN_COUNT];
+                quick_draw(ctx, player_count, (0., 0.2), &self.screen)?;
+                for (i, slot_text) in self.slot_texts.iter().take(self.player_count).enumerate() {
+                    quick_draw(ctx, slot_text, (0., 0.12 + i as f64 / -16.), &self.screen)?;
+                }
+                quick_draw(ctx, &self.level_texts[self.level], (0., -0.36), &self.screen)?;
+                quick_draw(ctx, &self.assets.single, (0., -0.42), &self.screen)?;
+                for text in &self.rules_text {
+                    quick_draw(ctx, text, (0., -0.47), &self.screen)?;
+                }
             }
+        }
+        Ok(())
+    }
+}
 
-            
+impl event::EventHandler for MainState {
+    fn update(&mut self, ctx: &mut Context, dt: Duration) -> GameResult<()> {
+        self.scenes.update(timer::duration_to_f64(dt));
+        if let Some(ref mut net) = self.net {
+            net.receive();
+        }
+        self.update_broadcast();
+        // Unplugged controllers let go of their llama and their slot. Plugged
+        // back in, they come with a new id and take a free slot again.
+        for id in self.controllers.update(ctx) {
+            self.gamepads.retain(|g| g.id != id);
+        }
+        if self.window_changed {
+            self.window_changed = false;
+            if self.fullscreen {
+                self.windowed_size = ctx.gfx_context.get_window().size();
+            }
+            set_fullscreen(ctx, self.fullscreen, self.windowed_size)?;
+            let (width, height) = ctx.gfx_context.get_window().drawable_size();
+            self.resize(ctx, width, height)?;
+        }
+        match self.scenes.top() {
+            // Game Scene
+            Scene::Game => {
+                if let Some((cooldown, text)) = self.banner.take() {
+                    let cooldown = cooldown - timer::duration_to_f64(dt);
+                    if cooldown > 0. {
+                        self.banner = Some((cooldown, text));
+                    }
+                }
+                let frame_time = timer::duration_to_f64(dt).min(Self::MAX_FRAME_TIME);
+                self.accumulator += frame_time;
+                while self.accumulator >= World::TICK {
+                    self.accumulator -= World::TICK;
+                    let ticks = match (self.playback.as_mut(), self.net.as_ref()) {
+                        (Some(playback), _) => playback.ticks_to_run(),
+                        (None, Some(net)) => net.ticks_to_run(),
+                        (None, None) => 1,
+                    };
+                    for _ in 0..ticks {
+                        self.tick(ctx)?;
+                    }
+                }
+            }
+            Scene::Pause => self.pause_menu.update(ctx, &self.assets)?,
+            Scene::Results => {
+                if self.results_texts.is_empty() {
+                    let title = match self.winner {
+                        Some(i) => format!("PLAYER {} WINS!", i + 1),
+                        None => "DRAW!".to_string(),
+                    };
+                    self.results_texts.push(graphics::Text::new(ctx, &title, &self.assets.font)?);
+                    for (i, stats) in self.world.stats.iter().enumerate() {
+                        let line = format!(
+                            "P{}  rounds {}  points {}  kills {}  deaths {}  bonuses {}",
+                            i + 1,
+                            stats.rounds_won,
+                            stats.points,
+                            stats.kills,
+                            stats.deaths,
+                            stats.bonuses
+                        );
+                        self.results_texts.push(graphics::Text::new(ctx, &line, &self.assets.font_small)?);
+                    }
+                    let help = match self.net {
+                        Some(_) => "BACKSPACE (or BACK): back to menu",
+                        None => "SPACE (or START): rematch, BACKSPACE (or BACK): back to menu",
+                    };
+                    self.results_texts.push(graphics::Text::new(ctx, help, &self.assets.font_small)?);
+                }
+            }
+            Scene::Controls => {
+                self.controls_menu.update(ctx, &self.controls, &self.assets)?;
+                if self.controls_changed {
+                    self.controls_changed = false;
+                    if let Err(e) = config::save_controls(ctx, &self.controls) {
+                        println!("Could not save controls: {}", e);
+                    }
+                }
+            }
+            Scene::Lobby => self.join_match(),
+            _ => {
+                if self.slot_texts.is_empty() {
+                    for (i, slot) in self.slots.iter().enumerate() {
+                        let text = match *slot {
+                            None => self.controls[i].describe(i),
+                            Some(difficulty) => format!("P{}: bot ({})", i + 1, difficulty.name()),
+                        };
+                        self.slot_texts.push(graphics::Text::new(ctx, &text, &self.assets.font_small)?);
+                    }
+                }
+                if self.rules_text.is_empty() {
+                    let text = self.rules.describe();
+                    self.rules_text.push(graphics::Text::new(ctx, &text, &self.assets.font_small)?);
+                }
+            }
         }
 
+        if let Some(ref mut net) = self.net {
+            net.send();
+        }
+        self.fps.update(ctx, dt);
+
+        Ok(())
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        graphics::clear(ctx);
+        let view = self.view();
+        self.decals.draw(
+            ctx,
+            &self.backgrounds[self.level],
+            &self.world.particle_definitions,
+            &view,
+            &self.assets,
+        )?;
+        for scene in self.scenes.visible().to_vec() {
+            self.draw_scene(ctx, scene)?;
+        }
+        let fade = self.scenes.fade_opacity();
+        if fade > 0. {
+            transparent_layer(ctx, &self.screen, fade)?;
+        }
+
+        draw_letterbox(ctx, &self.screen)?;
+
         graphics::present(ctx);
         timer::sleep(Duration::from_secs(0));
         Ok(())
     }
 
     fn key_down_event(&mut self, keycode: event::Keycode, _keymod: event::Mod, _repeat: bool) {
-        match self.scene {
+        // ESC is taken by ggez to quit. Keep controls::RESERVED in step
+        // with the keys handled here.
+        match keycode {
+            event::Keycode::F10 => {
+                let integer_scaling = !self.screen.is_integer_scaling();
+                return self.screen.set_integer_scaling(integer_scaling);
+            }
+            event::Keycode::F11 => {
+                self.fullscreen = !self.fullscreen;
+                self.window_changed = true;
+                return;
+            }
+            _ => (),
+        }
+        if self.scenes.top() == Scene::Game {
+            match keycode {
+                event::Keycode::P => return self.pause(),
+                event::Keycode::F5 => return self.quick_save(),
+                event::Keycode::F9 => return self.quick_load(),
+                _ if self.is_spectating() => return self.move_camera(keycode),
+                _ => (),
+            }
+        }
+        if let (Some(playback), Scene::Game) = (self.playback.as_mut(), self.scenes.top()) {
+            match keycode {
+                event::Keycode::Space => playback.toggle_pause(),
+                event::Keycode::Right => playback.step(),
+                event::Keycode::F => playback.toggle_fast_forward(),
+                _ => (),
+            }
+            return;
+        }
+        match self.scenes.top() {
             // Game Scene
-            Scene::Game => for player in &mut self.players {
-                if keycode == player.controls.up {
-                    player.input_axis.y = 1.0;
-                } else if keycode == player.controls.left {
-                    player.facing = Facing::Left;
-                    player.input_axis.x = -1.0;
-                } else if keycode == player.controls.right {
-                    player.facing = Facing::Right;
-                    player.input_axis.x = 1.0;
+            Scene::Game => for (controls, input) in self.controls.iter().zip(self.inputs.iter_mut()) {
+                if keycode == controls.up {
+                    input.y = 1.0;
+                } else if keycode == controls.left {
+                    input.x = -1.0;
+                } else if keycode == controls.right {
+                    input.x = 1.0;
                 }
             },
-            _ => if keycode == event::Keycode::Space {
-                self.scene = Scene::Game;
-            } else if keycode == event::Keycode::C {
-                self.scene = Scene::Credits;
+            Scene::Pause => if let Some(action) = self.pause_menu.key_down(keycode) {
+                self.pause_action(action);
+            },
+            Scene::Results => match keycode {
+                event::Keycode::Space => self.rematch(),
+                event::Keycode::Backspace => self.back_to_menu(),
+                _ => (),
             },
+            Scene::Lobby => if keycode == event::Keycode::Backspace {
+                self.back_to_menu();
+            },
+            Scene::Controls => {
+                if keycode == event::Keycode::Backspace && !self.controls_menu.waiting {
+                    self.slot_texts = vec![];
+                    self.scenes.pop();
+                } else if self.controls_menu.key_down(keycode, &mut self.controls, self.player_count) {
+                    self.controls_changed = true;
+                }
+            }
+            Scene::Credits => self.scenes.pop(),
+            Scene::Intro => match keycode {
+                event::Keycode::Space => self.start_match(),
+                event::Keycode::C => self.scenes.push(Scene::Credits),
+                event::Keycode::L => {
+                    self.level = (self.level + 1) % self.levels.len();
+                    self.decals.clear();
+                }
+                event::Keycode::S => {
+                    self.rules.next_score_limit();
+                    self.rules_text = vec![];
+                }
+                event::Keycode::T => {
+                    self.rules.next_time_limit();
+                    self.rules_text = vec![];
+                }
+                event::Keycode::B => {
+                    self.rules.next_rounds();
+                    self.rules_text = vec![];
+                }
+                event::Keycode::K => {
+                    self.controls_menu = ControlsMenu::new();
+                    self.scenes.push(Scene::Controls);
+                }
+                event::Keycode::Num2 => self.player_count = 2,
+                event::Keycode::Num3 => self.player_count = 3,
+                event::Keycode::Num4 => self.player_count = 4,
+                event::Keycode::Num5 => self.player_count = 5,
+                event::Keycode::Num6 => self.player_count = 6,
+                event::Keycode::Num7 => self.player_count = 7,
+                event::Keycode::Num8 => self.player_count = 8,
+                event::Keycode::F1 => self.switch_slot(0),
+                event::Keycode::F2 => self.switch_slot(1),
+                event::Keycode::F3 => self.switch_slot(2),
+                event::Keycode::F4 => self.switch_slot(3),
+                event::Keycode::F5 => self.switch_slot(4),
+                event::Keycode::F6 => self.switch_slot(5),
+                event::Keycode::F7 => self.switch_slot(6),
+                event::Keycode::F8 => self.switch_slot(7),
+                _ => (),
+            },
+        }
+    }
+
+    fn controller_button_down_event(&mut self, btn: event::Button, instance_id: i32) {
+        match (self.scenes.top(), btn) {
+            (Scene::Results, event::Button::Start) => return self.rematch(),
+            (Scene::Results, event::Button::Back) => return self.back_to_menu(),
+            (Scene::Lobby, event::Button::Back) => return self.back_to_menu(),
+            (Scene::Game, event::Button::Start) => return self.pause(),
+            (Scene::Pause, event::Button::Start) => return self.scenes.pop(),
+            (Scene::Pause, _) => {
+                // Same navigation as the keyboard
+                let keycode = match btn {
+                    event::Button::DPadUp => event::Keycode::Up,
+                    event::Button::DPadDown => event::Keycode::Down,
+                    event::Button::A => event::Keycode::Return,
+                    _ => return,
+                };
+                if let Some(action) = self.pause_menu.key_down(keycode) {
+                    self.pause_action(action);
+                }
+                return;
+            }
+            _ => (),
+        }
+        if self.playback.is_some() {
+            return;
+        }
+        if let Some(gamepad) = self.gamepad(instance_id) {
+            gamepad.button(btn, true);
+        }
+        match (self.scenes.top(), btn) {
+            (Scene::Intro, event::Button::Start) | (Scene::Credits, event::Button::Start) => self.start_match(),
+            _ => (),
+        }
+    }
+
+    fn controller_button_up_event(&mut self, btn: event::Button, instance_id: i32) {
+        if let Some(gamepad) = self.gamepads.iter_mut().find(|g| g.id == instance_id) {
+            gamepad.button(btn, false);
+        }
+    }
+
+    fn controller_axis_event(&mut self, axis: event::Axis, value: i16, instance_id: i32) {
+        if self.playback.is_some() {
+            return;
+        }
+        if let Some(gamepad) = self.gamepad(instance_id) {
+            gamepad.motion(axis, value);
         }
     }
 
     fn key_up_event(&mut self, keycode: event::Keycode, _keymod: event::Mod, _repeat: bool) {
-        for player in &mut self.players {
-            if keycode == player.controls.up {
-                player.input_axis.y = 0.0;
-            } else if keycode == player.controls.left {
-                if player.input_axis.x < 0. {
-                    player.input_axis.x = 0.0;
+        for (controls, input) in self.controls.iter().zip(self.inputs.iter_mut()) {
+            if keycode == controls.up {
+                input.y = 0.0;
+            } else if keycode == controls.left {
+                if input.x < 0. {
+                    input.x = 0.0;
                 }
-            } else if keycode == player.controls.right {
-                if player.input_axis.x > 0. {
-                    player.input_axis.x = 0.0;
+            } else if keycode == controls.right {
+                if input.x > 0. {
+                    input.x = 0.0;
                 }
             }
         }
     }
+
+    fn resize_event(&mut self, ctx: &mut Context, width: u32, height: u32) {
+        if let Err(e) = self.resize(ctx, width, height) {
+            println!("Could not resize: {}", e);
+        }
+    }
+
+    fn mouse_wheel_event(&mut self, _x: i32, y: i32) {
+        if self.is_spectating() && self.scenes.top() == Scene::Game {
+            self.camera.zoom(y);
+        }
+    }
+}
+
+// Keyboard and controllers can drive the same llama. Without a slot, as in
+// network games, every controller does.
+fn human_axis(axis: Vector2<f64>, gamepads: &[Gamepad], slot: Option<usize>) -> Vector2<f64> {
+    let mut axis = axis;
+    for gamepad in gamepads.iter().filter(|g| slot.map_or(true, |slot| g.slot == slot)) {
+        let pad = gamepad.axis();
+        axis.x = (axis.x + pad.x).max(-1.).min(1.);
+        axis.y = axis.y.max(pad.y);
+    }
+    axis
 }
 
+// "round 2/3  1:23", empty when there is nothing to count
+fn match_label(world: &World) -> String {
+    let mut label = String::new();
+    if world.rules.rounds > 1 {
+        label.push_str(&format!("round {}/{}  ", world.round, world.rules.rounds));
+    }
+    if let Some(time_left) = world.time_left() {
+        label.push_str(&rules::clock(time_left));
+    }
+    label
+}
 
 pub fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}", e);
+            println!("Usage: llamassacre [--seed <number>] [--players <2-8>] [--level <name>]");
+            println!("                   [--score-limit <points>] [--time-limit <seconds>] [--rounds <count>]");
+            println!("                   [--record <file>] [--replay <file>] [--state <file>]");
+            println!("                   [--host <port> [--rollback] [--broadcast-delay <seconds>]]");
+            println!("                   [--join <address[:port]>] [--watch <address[:port]>]");
+            println!("                   [--fullscreen] [--integer-scaling]");
+            return;
+        }
+    };
+    println!("Seed: {}", options.seed);
     let mut c = conf::Conf::new();
     c.window_title = "Llamassacre".to_string();
     c.window_width = Screen::WIDTH;
     c.window_height = Screen::HEIGHT;
+    c.resizable = true;
     let ctx = &mut Context::load_from_conf("Llamassacre", "ggez", c).unwrap();
-    let state = &mut MainState::new(ctx).unwrap();
+    let state = &mut MainState::new(ctx, &options).unwrap();
     if let Err(e) = event::run(ctx, state) {
         println!("Oops. Error encountered: {}", e);
     } else {
         println!("Great. Game exited cleanly.");
     }
+    state.finish_recording();
+}
+
+// Fullscreen at the desktop resolution, or back in a window of the given size
+fn set_fullscreen(ctx: &mut Context, fullscreen: bool, windowed_size: (u32, u32)) -> GameResult<()> {
+    let fullscreen_type = if fullscreen {
+        graphics::FullscreenType::Desktop
+    } else {
+        graphics::FullscreenType::Off
+    };
+    let mode = graphics::WindowMode::default()
+        .fullscreen_type(fullscreen_type)
+        .vsync(ctx.conf.vsync);
+    graphics::set_mode(ctx, windowed_size.0, windowed_size.1, mode)
+}
+
+// The path itself the first time, then with the number before the extension
+fn numbered_path(path: &str, n: usize) -> String {
+    if n <= 1 {
+        return path.to_string();
+    }
+    let path = Path::new(path);
+    let stem = path.file_stem().map_or("replay".into(), |s| s.to_string_lossy());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, n, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
 }
@@ -0,0 +1,156 @@
+use ggez::event::Keycode;
+use ggez::graphics;
+use ggez::{Context, GameResult};
+use assets::Assets;
+use controls::{self, Action, Controls};
+
+// Controls settings screen: one page per player, pick an action and press
+// the new key for it
+pub struct ControlsMenu {
+    pub player: usize,
+    pub action: usize,
+    pub waiting: bool,
+    pub message: String,
+    pub texts: Vec<graphics::Text>,
+}
+
+impl ControlsMenu {
+    pub fn new() -> ControlsMenu {
+        ControlsMenu {
+            player: 0,
+            action: 0,
+            waiting: false,
+            message: String::new(),
+            texts: vec![],
+        }
+    }
+
+    // Returns true when a binding changed
+    pub fn key_down(&mut self, keycode: Keycode, controls: &mut [Controls], player_count: usize) -> bool {
+        self.texts = vec![];
+        let action = Action::ALL[self.action];
+        if self.waiting {
+            self.waiting = false;
+            if keycode == Keycode::Backspace {
+                self.message = String::new();
+                return false;
+            }
+            if let Some(name) = controls::reserved(keycode) {
+                self.message = format!("{} is already used by {}", keycode.name(), name);
+                return false;
+            }
+            match controls::find(controls, keycode) {
+                Some((i, a)) if i != self.player || a != action => {
+                    self.message = format!("{} is already used by P{} {}", keycode.name(), i + 1, a.name());
+                    false
+                }
+                _ => {
+                    controls[self.player].set(action, keycode);
+                    self.message = String::new();
+                    true
+                }
+            }
+        } else {
+            match keycode {
+                Keycode::Up => self.action = (self.action + Action::ALL.len() - 1) % Action::ALL.len(),
+                Keycode::Down => self.action = (self.action + 1) % Action::ALL.len(),
+                Keycode::Left => self.player = (self.player + player_count - 1) % player_count,
+                Keycode::Right => self.player = (self.player + 1) % player_count,
+                Keycode::Return => {
+                    self.waiting = true;
+                    self.message = format!("press a key for P{} {}", self.player + 1, action.name());
+                }
+                _ => (),
+            }
+            false
+        }
+    }
+
+    pub fn update(&mut self, ctx: &mut Context, controls: &[Controls], assets: &Assets) -> GameResult<()> {
+        if !self.texts.is_empty() {
+            return Ok(());
+        }
+        let mut lines = vec![format!("CONTROLS - player {} (left/right)", self.player + 1)];
+        for (i, action) in Action::ALL.iter().enumerate() {
+            let cursor = if i == self.action { ">" } else { " " };
+            let key = controls[self.player].get(*action).name();
+            lines.push(format!("{} {}: {}", cursor, action.name(), key));
+        }
+        lines.push("up/down: select, RETURN: rebind, BACKSPACE: back".to_string());
+        lines.push(self.message.clone());
+        for line in lines {
+            // Empty texts can't be rendered
+            let line = if line.is_empty() { " ".to_string() } else { line };
+            self.texts.push(graphics::Text::new(ctx, &line, &assets.font_small)?);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PauseAction {
+    Resume,
+    Restart,
+    Settings,
+    Quit,
+}
+
+impl PauseAction {
+    pub const ALL: [PauseAction; 4] = [
+        PauseAction::Resume,
+        PauseAction::Restart,
+        PauseAction::Settings,
+        PauseAction::Quit,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PauseAction::Resume => "resume",
+            PauseAction::Restart => "restart",
+            PauseAction::Settings => "settings",
+            PauseAction::Quit => "quit to menu",
+        }
+    }
+}
+
+// Shown over the frozen game
+pub struct PauseMenu {
+    pub selected: usize,
+    pub texts: Vec<graphics::Text>,
+}
+
+impl PauseMenu {
+    pub fn new() -> PauseMenu {
+        PauseMenu {
+            selected: 0,
+            texts: vec![],
+        }
+    }
+
+    // Returns the chosen action, if any
+    pub fn key_down(&mut self, keycode: Keycode) -> Option<PauseAction> {
+        self.texts = vec![];
+        let count = PauseAction::ALL.len();
+        match keycode {
+            Keycode::Up => self.selected = (self.selected + count - 1) % count,
+            Keycode::Down => self.selected = (self.selected + 1) % count,
+            Keycode::Return => return Some(PauseAction::ALL[self.selected]),
+            Keycode::P => return Some(PauseAction::Resume),
+            _ => (),
+        }
+        None
+    }
+
+    pub fn update(&mut self, ctx: &mut Context, assets: &Assets) -> GameResult<()> {
+        if !self.texts.is_empty() {
+            return Ok(());
+        }
+        self.texts.push(graphics::Text::new(ctx, "PAUSE", &assets.font)?);
+        for (i, action) in PauseAction::ALL.iter().enumerate() {
+            let cursor = if i == self.selected { ">" } else { " " };
+            let line = format!("{} {}", cursor, action.name());
+            self.texts.push(graphics::Text::new(ctx, &line, &assets.font_small)?);
+        }
+        Ok(())
+    }
+}
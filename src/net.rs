@@ -0,0 +1,1020 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use input::Input;
+use rules::Rules;
+use snapshot::Snapshot;
+
+// Network games over UDP with the host in the middle. Every machine sends the
+// input of its own llama for a tick a few ticks ahead, the host passes them on
+// to everyone else. In lockstep nobody simulates a tick before having the
+// inputs of every player for it; with rollback the game runs ahead on guesses
+// and corrects itself, see rollback.rs. Either way all the worlds end up the
+// same as long as the simulation is deterministic, which checksums check.
+// Spectators get the world as it was some time ago from the host, then the
+// inputs of every player from there.
+//
+// Packets, little endian: "LLNT", protocol version (u8), kind (u8), then
+//   HELLO    nothing, a client looking for a game
+//   WELCOME  slot (u8), seed (u64), player count (u8), score limit (u32),
+//            time limit (u32), rounds (u32), rollback (u8),
+//            level name length (u32), level name
+//   FULL     nothing, no slot left
+//   INPUTS   player count (u8), ticks known for each player (u32 each),
+//            run count (u8), runs: slot (u8), first tick (u32), count (u8),
+//            count (x, y) i8 pairs, then ticks simulated (u32) and the
+//            checksum of the last one (u64)
+//   WATCH    ticks known (u32), 0xFFFFFFFF until a STATE came, a spectator
+//            looking for a game or keeping up with one
+//   STATE    the same as WELCOME, then the tick (u32), piece index (u16),
+//            piece count (u16) and that piece of the encoded snapshot of the
+//            world before the tick, particles left out
+//   FEED     first tick (u32), count (u8), then for every tick one (x, y)
+//            i8 pair per player
+// Runs start from what the receiver says it knows, so a lost packet only
+// costs some latency: the next one repeats it.
+const MAGIC: &'static [u8; 4] = b"LLNT";
+const VERSION: u8 = 9;
+const HELLO: u8 = 0;
+const WELCOME: u8 = 1;
+const FULL: u8 = 2;
+const INPUTS: u8 = 3;
+const WATCH: u8 = 4;
+const STATE: u8 = 5;
+const FEED: u8 = 6;
+const NO_STATE: u32 = 0xFFFF_FFFF;
+// The biggest UDP packet there is
+const MAX_PACKET: usize = 65536;
+// States are sent in pieces of this many bytes, big packets get lost more
+const PIECE: usize = 1024;
+
+// What every machine needs to set up the same match
+#[derive(Debug, Clone)]
+pub struct Setup {
+    // The player controlled from this machine
+    pub slot: usize,
+    pub seed: u64,
+    pub players: usize,
+    pub level: String,
+    pub rules: Rules,
+    // Predict and correct instead of waiting for every input
+    pub rollback: bool,
+}
+
+// What sessions talk through, a UDP socket except in tests. Never waits: with
+// nothing to read it says WouldBlock.
+pub trait Socket {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Socket for UdpSocket {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, packet, address)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+struct Peer {
+    address: SocketAddr,
+    slot: usize,
+    // Ticks it knows the inputs of, for each player
+    known: Vec<usize>,
+    // Ticks it confirmed, with a checksum
+    simulated: usize,
+    last_heard: Instant,
+}
+
+struct Spectator {
+    address: SocketAddr,
+    // Ticks it can play, None until it has a state
+    known: Option<usize>,
+    last_heard: Instant,
+}
+
+pub struct Session {
+    socket: Box<Socket>,
+    host: bool,
+    // Watches without playing
+    spectator: bool,
+    pub setup: Option<Setup>,
+    // The clients for the host, the host for a client
+    peers: Vec<Peer>,
+    // Inputs of every player by tick from the first kept, None until known
+    inputs: VecDeque<Vec<Option<Input>>>,
+    // Ticks everybody confirmed, their inputs are not needed any more
+    forgotten: usize,
+    // Ticks in a row with a known input, for each player
+    known: Vec<usize>,
+    // Next tick the game simulates
+    tick: usize,
+    // Next tick to give the local input for
+    local_tick: usize,
+    // Ours, by tick, the last ones only
+    checksums: VecDeque<u64>,
+    // Ticks we confirmed, with a checksum
+    confirmed: usize,
+    // Theirs, waiting for us to simulate that far
+    remote_checksums: Vec<(usize, u64)>,
+    desync: Option<usize>,
+    lost: Option<String>,
+    last_hello: Option<Instant>,
+    stalled_since: Option<Instant>,
+    spectators: Vec<Spectator>,
+    // Seconds spectators lag behind the players
+    pub broadcast_delay: f64,
+    // When ticks got their last input, for the delay
+    completed: VecDeque<(usize, Instant)>,
+    // Ticks spectators may see
+    broadcast_tick: usize,
+    // The world spectators start from, encoded, and its tick
+    broadcast_state: Option<(usize, Vec<u8>)>,
+    // The pieces of the state a spectator got so far, and its tick
+    pieces: Option<(usize, Vec<Option<Vec<u8>>>)>,
+    // What a spectator got to start from, until the game takes it
+    watched: Option<Snapshot>,
+}
+
+impl Session {
+    pub const DEFAULT_PORT: u16 = 7777;
+    // Ticks between pressing a key and the llama moving, hides the latency
+    const LOCKSTEP_DELAY: usize = 4;
+    // Rollback hides it, a little delay still saves some corrections
+    const ROLLBACK_DELAY: usize = 1;
+    // Ticks sent at most in one run
+    const MAX_BATCH: usize = 32;
+    // Ticks past the known ones a packet may talk about, more is garbage
+    const MAX_AHEAD: usize = 256;
+    const HELLO_INTERVAL: f64 = 0.25;
+    const TIMEOUT: f64 = 5.;
+    // Short stalls are not worth a message
+    const STALL_NOTICE: f64 = 0.25;
+    const MAX_SPECTATORS: usize = 16;
+
+    pub fn host(port: u16, setup: Setup) -> io::Result<Session> {
+        Ok(Session::host_on(udp_socket(port)?, setup))
+    }
+
+    pub fn host_on(socket: Box<Socket>, setup: Setup) -> Session {
+        let mut session = Session::new(socket, true);
+        session.start(setup);
+        session
+    }
+
+    pub fn join(address: &str) -> io::Result<Session> {
+        Session::connect(address, false)
+    }
+
+    pub fn watch(address: &str) -> io::Result<Session> {
+        Session::connect(address, true)
+    }
+
+    fn connect(address: &str, spectator: bool) -> io::Result<Session> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "no such host"))?;
+        Ok(Session::connect_on(udp_socket(0)?, address, spectator))
+    }
+
+    pub fn connect_on(socket: Box<Socket>, address: SocketAddr, spectator: bool) -> Session {
+        let mut session = Session::new(socket, false);
+        session.spectator = spectator;
+        session.peers.push(Peer {
+            address: address,
+            slot: 0,
+            known: vec![],
+            simulated: 0,
+            last_heard: Instant::now(),
+        });
+        session
+    }
+
+    fn new(socket: Box<Socket>, host: bool) -> Session {
+        Session {
+            socket: socket,
+            host: host,
+            spectator: false,
+            setup: None,
+            peers: vec![],
+            inputs: VecDeque::new(),
+            forgotten: 0,
+            known: vec![],
+            tick: 0,
+            local_tick: 0,
+            checksums: VecDeque::new(),
+            confirmed: 0,
+            remote_checksums: vec![],
+            desync: None,
+            lost: None,
+            last_hello: None,
+            stalled_since: None,
+            spectators: vec![],
+            broadcast_delay: 0.,
+            completed: VecDeque::new(),
+            broadcast_tick: 0,
+            broadcast_state: None,
+            pieces: None,
+            watched: None,
+        }
+    }
+
+    // Nobody moves during the first ticks, they are gone before any input arrives
+    fn start(&mut self, setup: Setup) {
+        let delay = if setup.rollback {
+            Self::ROLLBACK_DELAY
+        } else {
+            Self::LOCKSTEP_DELAY
+        };
+        self.inputs = (0..delay).map(|_| vec![Some(Input::default()); setup.players]).collect();
+        self.known = vec![delay; setup.players];
+        self.local_tick = delay;
+        self.setup = Some(setup);
+    }
+
+    fn delay(&self) -> usize {
+        match self.setup {
+            Some(ref setup) if setup.rollback => Self::ROLLBACK_DELAY,
+            _ => Self::LOCKSTEP_DELAY,
+        }
+    }
+
+    // Everybody is there, the match can start. Clients start as soon as they
+    // are welcome, the game waits for the host from there.
+    pub fn is_ready(&self) -> bool {
+        match self.setup {
+            Some(ref setup) => !self.host || self.peers.len() + 1 == setup.players,
+            None => false,
+        }
+    }
+
+    pub fn is_rollback(&self) -> bool {
+        self.setup.as_ref().map_or(false, |s| s.rollback)
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+    // The input of the local player, kept a few ticks ahead of the simulation
+    pub fn add_local_input(&mut self, input: Input) {
+        let (slot, players) = match self.setup {
+            Some(ref setup) if !self.spectator => (setup.slot, setup.players),
+            _ => return,
+        };
+        while self.local_tick < self.tick + self.delay() {
+            let tick = self.local_tick;
+            if let Some(inputs) = self.inputs_at(tick, players) {
+                inputs[slot] = Some(input);
+            }
+            self.local_tick += 1;
+        }
+        self.update_known();
+    }
+
+    // Ticks in a row known for a player
+    pub fn known(&self, slot: usize) -> usize {
+        self.known.get(slot).cloned().unwrap_or(0)
+    }
+
+    // None once forgotten too
+    pub fn input(&self, tick: usize, slot: usize) -> Option<Input> {
+        if tick < self.forgotten {
+            return None;
+        }
+        self.inputs.get(tick - self.forgotten).and_then(|inputs| inputs[slot])
+    }
+
+    // Ticks of inputs kept in memory
+    pub fn kept(&self) -> usize {
+        self.inputs.len()
+    }
+
+    // The inputs of a tick, made room for, None once forgotten
+    fn inputs_at(&mut self, tick: usize, players: usize) -> Option<&mut Vec<Option<Input>>> {
+        if tick < self.forgotten {
+            return None;
+        }
+        while self.forgotten + self.inputs.len() <= tick {
+            self.inputs.push_back(vec![None; players]);
+        }
+        self.inputs.get_mut(tick - self.forgotten)
+    }
+
+    // Inputs of the ticks from first, which is still kept
+    fn inputs_from(&self, first: usize, count: usize) -> Vec<&Vec<Option<Input>>> {
+        self.inputs.iter().skip(first - self.forgotten).take(count).collect()
+    }
+
+    // Ticks with the inputs of every player
+    fn complete(&self) -> usize {
+        self.known.iter().cloned().min().unwrap_or(0)
+    }
+
+    // The inputs for the next tick in lockstep, None while someone is late or
+    // if the game cannot go on
+    pub fn next_inputs(&mut self) -> Option<Vec<Input>> {
+        let blocked = self.lost.is_some() || self.desync.is_some() || self.tick >= self.complete();
+        self.set_stalled(blocked);
+        if blocked {
+            return None;
+        }
+        let inputs = self.inputs[self.tick - self.forgotten].iter().map(|i| i.unwrap()).collect();
+        self.tick += 1;
+        Some(inputs)
+    }
+
+    // With rollback the game keeps track of the ticks itself
+    pub fn set_tick(&mut self, tick: usize) {
+        self.tick = tick;
+    }
+
+    pub fn set_stalled(&mut self, stalled: bool) {
+        if !stalled {
+            self.stalled_since = None;
+        } else if self.stalled_since.is_none() {
+            self.stalled_since = Some(Instant::now());
+        }
+    }
+
+    // Nothing more can be simulated safely
+    pub fn is_blocked(&self) -> bool {
+        self.lost.is_some() || self.desync.is_some()
+    }
+
+    // Two ticks at once when the others are ahead, to catch up
+    pub fn ticks_to_run(&self) -> usize {
+        if self.complete() > self.tick + self.delay() { 2 } else { 1 }
+    }
+
+    // Called with the state of the world after every confirmed tick
+    pub fn record_checksum(&mut self, checksum: u64) {
+        self.checksums.push_back(checksum);
+        self.confirmed += 1;
+        let simulated = self.confirmed;
+        let pending = mem::replace(&mut self.remote_checksums, vec![]);
+        for (tick, checksum) in pending {
+            if tick <= simulated {
+                self.compare_checksum(tick, checksum);
+            } else {
+                self.remote_checksums.push((tick, checksum));
+            }
+        }
+    }
+
+    // Ticks recorded a checksum for
+    pub fn confirmed(&self) -> usize {
+        self.confirmed
+    }
+
+    // Ticks spectators may see, the world they start from has to stay behind
+    pub fn broadcast_tick(&self) -> usize {
+        self.broadcast_tick
+    }
+
+    // Somebody came to watch, the world as they are to see it is needed. It
+    // stays the same until they all have it, they get it in pieces.
+    pub fn wants_state(&self) -> bool {
+        self.broadcast_state.is_none() && self.spectators.iter().any(|s| s.known.is_none())
+    }
+
+    // The world spectators see, before the given tick. Particles are only
+    // for show and many, spectators do without the ones already flying.
+    pub fn set_state(&mut self, tick: usize, snapshot: &Snapshot) {
+        let mut snapshot = snapshot.clone();
+        snapshot.particles.clear();
+        self.broadcast_state = Some((tick, snapshot.encode()));
+    }
+
+    // For a spectator, the world to start from once it came
+    pub fn take_state(&mut self) -> Option<Snapshot> {
+        self.watched.take()
+    }
+
+    // What is wrong, None when all is well
+    pub fn status(&self) -> Option<String> {
+        if let Some(ref reason) = self.lost {
+            return Some(format!("connection lost: {}", reason));
+        }
+        if let Some(tick) = self.desync {
+            return Some(format!("desync at tick {}, the games differ", tick));
+        }
+        let setup = match self.setup {
+            Some(ref setup) => setup,
+            None if self.spectator => return Some(format!("connecting to {} to watch...", self.peers[0].address)),
+            None => return Some(format!("joining {}...", self.peers[0].address)),
+        };
+        if !self.is_ready() {
+            let port = self.socket.local_addr().map(|a| a.port()).unwrap_or(0);
+            return Some(format!(
+                "waiting for players on port {} ({}/{})",
+                port,
+                self.peers.len() + 1,
+                setup.players
+            ));
+        }
+        match self.stalled_since {
+            Some(since) if self.spectator && seconds(since.elapsed()) > Self::STALL_NOTICE => {
+                Some("waiting for the broadcast".to_string())
+            }
+            Some(since) if seconds(since.elapsed()) > Self::STALL_NOTICE => {
+                // The oldest inputs missing hold everything up
+                let complete = self.complete();
+                let late: Vec<String> = (0..setup.players)
+                    .filter(|&slot| self.known[slot] == complete)
+                    .map(|slot| format!("P{}", slot + 1))
+                    .collect();
+                Some(format!("waiting for {}", late.join(" ")))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn receive(&mut self) {
+        let mut buffer = [0; MAX_PACKET];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((size, from)) => {
+                    let packet = buffer[..size].to_vec();
+                    self.handle(&packet, from);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Some systems report an unreachable peer here, the timeout deals with it
+                Err(_) => break,
+            }
+        }
+    }
+
+    pub fn send(&mut self) {
+        let now = Instant::now();
+        let silent = self.peers
+            .iter()
+            .find(|p| seconds(now.duration_since(p.last_heard)) > Self::TIMEOUT)
+            .map(|p| p.slot);
+        if let (None, Some(slot)) = (self.lost.as_ref(), silent) {
+            self.lost = Some(if self.host {
+                format!("P{} left", slot + 1)
+            } else {
+                "no news from the host".to_string()
+            });
+        }
+        if self.lost.is_some() {
+            return;
+        }
+        self.forget();
+        if self.spectator {
+            return self.send_watch(now);
+        }
+        if !self.host && self.setup.is_none() {
+            let due = self.last_hello
+                .map_or(true, |t| seconds(now.duration_since(t)) > Self::HELLO_INTERVAL);
+            if due {
+                self.last_hello = Some(now);
+                let address = self.peers[0].address;
+                self.send_to(&header(HELLO), address);
+            }
+            return;
+        }
+        // Also keeps the ones waiting in the lobby from timing out
+        for peer in &self.peers {
+            self.send_to(&self.inputs_packet(peer), peer.address);
+        }
+        if self.host {
+            self.broadcast(now);
+        }
+    }
+
+    // Asks for a state until one comes, then says how far it got
+    fn send_watch(&mut self, now: Instant) {
+        let known = match self.setup {
+            Some(_) => self.complete() as u32,
+            None => {
+                let due = self.last_hello
+                    .map_or(true, |t| seconds(now.duration_since(t)) > Self::HELLO_INTERVAL);
+                if !due {
+                    return;
+                }
+                self.last_hello = Some(now);
+                NO_STATE
+            }
+        };
+        let mut packet = header(WATCH);
+        packet.extend_from_slice(&u32_to_bytes(known));
+        let address = self.peers[0].address;
+        self.send_to(&packet, address);
+    }
+
+    // Spectators get a state to start from, then the inputs old enough to show
+    fn broadcast(&mut self, now: Instant) {
+        while let Some(&(tick, at)) = self.completed.front() {
+            if seconds(now.duration_since(at)) < self.broadcast_delay {
+                break;
+            }
+            self.broadcast_tick = tick;
+            self.completed.pop_front();
+        }
+        // Those gone do not say so
+        self.spectators
+            .retain(|s| seconds(now.duration_since(s.last_heard)) < Self::TIMEOUT);
+        // The next ones to come get a newer one
+        if self.spectators.iter().all(|s| s.known.is_some()) {
+            self.broadcast_state = None;
+        }
+        let players = self.setup.as_ref().map_or(0, |s| s.players);
+        for spectator in &self.spectators {
+            let packet = match (spectator.known, &self.broadcast_state) {
+                (None, &Some((tick, ref state))) => {
+                    for packet in self.state_packets(tick, state) {
+                        self.send_to(&packet, spectator.address);
+                    }
+                    continue;
+                }
+                (None, &None) => continue,
+                // Empty ones keep it from timing out
+                (Some(first), _) => {
+                    let first = first.max(self.forgotten);
+                    let count = self.broadcast_tick.saturating_sub(first).min(Self::MAX_BATCH);
+                    let mut packet = header(FEED);
+                    packet.extend_from_slice(&u32_to_bytes(first as u32));
+                    packet.push(count as u8);
+                    for inputs in self.inputs_from(first, count) {
+                        for input in &inputs[..players] {
+                            let input = input.unwrap_or_default();
+                            packet.push(input.x as u8);
+                            packet.push(input.y as u8);
+                        }
+                    }
+                    packet
+                }
+            };
+            self.send_to(&packet, spectator.address);
+        }
+    }
+
+    fn state_packets(&self, tick: usize, state: &[u8]) -> Vec<Vec<u8>> {
+        let count = (state.len() + PIECE - 1) / PIECE;
+        state
+            .chunks(PIECE)
+            .enumerate()
+            .map(|(index, piece)| {
+                let mut packet = header(STATE);
+                if let Some(ref setup) = self.setup {
+                    write_setup(&mut packet, setup, 0);
+                }
+                packet.extend_from_slice(&u32_to_bytes(tick as u32));
+                packet.extend_from_slice(&u16_to_bytes(index as u16));
+                packet.extend_from_slice(&u16_to_bytes(count as u16));
+                packet.extend_from_slice(piece);
+                packet
+            })
+            .collect()
+    }
+
+    fn send_to(&self, packet: &[u8], address: SocketAddr) {
+        // Lost like any other packet, the next one repeats it
+        let _ = self.socket.send_to(packet, address);
+    }
+
+    fn handle(&mut self, packet: &[u8], from: SocketAddr) {
+        let mut reader = Reader::new(packet);
+        let kind = match reader.header() {
+            Some(kind) => kind,
+            None => return,
+        };
+        if self.host {
+            match kind {
+                HELLO => self.welcome(from),
+                INPUTS => {
+                    let _ = self.read_inputs(&mut reader, from);
+                }
+                WATCH => if let Some(known) = reader.u32() {
+                    self.watched_by(from, known);
+                },
+                _ => (),
+            }
+            return;
+        }
+        if from != self.peers[0].address {
+            return;
+        }
+        self.peers[0].last_heard = Instant::now();
+        match kind {
+            WELCOME if !self.spectator && self.setup.is_none() => match read_setup(&mut reader) {
+                // Slot 0 is the host's
+                Some(setup) if setup.slot != 0 => self.start(setup),
+                _ => (),
+            },
+            FULL => self.lost = Some("the game is full".to_string()),
+            INPUTS if !self.spectator => {
+                let _ = self.read_inputs(&mut reader, from);
+            }
+            STATE if self.spectator && self.setup.is_none() => {
+                let _ = self.read_state(&mut reader);
+            }
+            FEED if self.spectator && self.setup.is_some() => {
+                let _ = self.read_feed(&mut reader);
+            }
+            _ => (),
+        }
+    }
+
+    fn watched_by(&mut self, from: SocketAddr, known: u32) {
+        let known = if known == NO_STATE { None } else { Some(known as usize) };
+        match self.spectators.iter().position(|s| s.address == from) {
+            Some(i) => {
+                let broadcast_tick = self.broadcast_tick;
+                let spectator = &mut self.spectators[i];
+                spectator.last_heard = Instant::now();
+                // Packets come out of order
+                spectator.known = match (spectator.known, known) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    (a, b) => a.or(b),
+                };
+                // Ticks not broadcast yet are nonsense
+                if spectator.known.map_or(false, |k| k > broadcast_tick) {
+                    spectator.known = None;
+                }
+            }
+            None if self.spectators.len() < Self::MAX_SPECTATORS => self.spectators.push(Spectator {
+                address: from,
+                known: None,
+                last_heard: Instant::now(),
+            }),
+            None => self.send_to(&header(FULL), from),
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut Reader) -> Option<()> {
+        let mut setup = read_setup(reader)?;
+        let tick = reader.u32()? as usize;
+        let index = reader.u16()? as usize;
+        let count = reader.u16()? as usize;
+        if index >= count {
+            return None;
+        }
+        // A new state from the host, the pieces of the old one are no use
+        let stale = self.pieces
+            .as_ref()
+            .map_or(true, |&(t, ref pieces)| t != tick || pieces.len() != count);
+        if stale {
+            self.pieces = Some((tick, vec![None; count]));
+        }
+        let state = {
+            let pieces = &mut self.pieces.as_mut()?.1;
+            pieces[index] = Some(reader.bytes.to_vec());
+            if pieces.iter().any(|p| p.is_none()) {
+                return Some(());
+            }
+            pieces
+                .iter()
+                .flat_map(|p| p.as_ref().unwrap().iter().cloned())
+                .collect::<Vec<u8>>()
+        };
+        self.pieces = None;
+        let snapshot = match Snapshot::decode(&state) {
+            Some(ref snapshot) if snapshot.players.len() != setup.players => {
+                println!(
+                    "Broadcast state has {} players instead of {}",
+                    snapshot.players.len(),
+                    setup.players
+                );
+                return None;
+            }
+            Some(snapshot) => snapshot,
+            None => {
+                println!("Could not decode the broadcast state ({} bytes)", state.len());
+                return None;
+            }
+        };
+        // Spectators just follow, whatever the players do
+        setup.rollback = false;
+        self.inputs = VecDeque::new();
+        self.forgotten = tick;
+        self.confirmed = tick;
+        self.known = vec![tick; setup.players];
+        self.tick = tick;
+        self.local_tick = tick;
+        self.setup = Some(setup);
+        self.watched = Some(snapshot);
+        Some(())
+    }
+
+    fn read_feed(&mut self, reader: &mut Reader) -> Option<()> {
+        let players = self.setup.as_ref()?.players;
+        let first = reader.u32()? as usize;
+        let count = reader.u8()? as usize;
+        if first > self.complete() {
+            return None;
+        }
+        for tick in first..first + count {
+            for slot in 0..players {
+                let input = reader.input()?;
+                if let Some(inputs) = self.inputs_at(tick, players) {
+                    if inputs[slot].is_none() {
+                        inputs[slot] = Some(input);
+                    }
+                }
+            }
+        }
+        self.update_known();
+        Some(())
+    }
+
+    fn welcome(&mut self, from: SocketAddr) {
+        let setup = match self.setup {
+            Some(ref setup) => setup.clone(),
+            None => return,
+        };
+        let slot = match self.peers.iter().position(|p| p.address == from) {
+            // The welcome got lost
+            Some(i) => {
+                self.peers[i].last_heard = Instant::now();
+                self.peers[i].slot
+            }
+            None if self.peers.len() + 1 < setup.players => {
+                let slot = self.peers.len() + 1;
+                self.peers.push(Peer {
+                    address: from,
+                    slot: slot,
+                    known: vec![],
+                    simulated: 0,
+                    last_heard: Instant::now(),
+                });
+                slot
+            }
+            None => return self.send_to(&header(FULL), from),
+        };
+        let mut packet = header(WELCOME);
+        write_setup(&mut packet, &setup, slot);
+        self.send_to(&packet, from);
+    }
+
+    // What the peer is missing: the host passes on everybody else's inputs,
+    // a client only has its own to give
+    fn inputs_packet(&self, peer: &Peer) -> Vec<u8> {
+        let own = self.setup.as_ref().map_or(0, |s| s.slot);
+        let mut runs = vec![];
+        for slot in 0..self.known.len() {
+            if slot == peer.slot || (!self.host && slot != own) {
+                continue;
+            }
+            let first = peer.known.get(slot).cloned().unwrap_or(0).max(self.forgotten);
+            let count = self.known[slot].saturating_sub(first).min(Self::MAX_BATCH);
+            if count > 0 {
+                runs.push((slot, first, count));
+            }
+        }
+        let mut packet = header(INPUTS);
+        packet.push(self.known.len() as u8);
+        for known in &self.known {
+            packet.extend_from_slice(&u32_to_bytes(*known as u32));
+        }
+        packet.push(runs.len() as u8);
+        for (slot, first, count) in runs {
+            packet.push(slot as u8);
+            packet.extend_from_slice(&u32_to_bytes(first as u32));
+            packet.push(count as u8);
+            for inputs in self.inputs_from(first, count) {
+                let input = inputs[slot].unwrap_or_default();
+                packet.push(input.x as u8);
+                packet.push(input.y as u8);
+            }
+        }
+        let checksum = self.checksums.back().cloned().unwrap_or(0);
+        packet.extend_from_slice(&u32_to_bytes(self.confirmed as u32));
+        packet.extend_from_slice(&u64_to_bytes(checksum));
+        packet
+    }
+
+    fn read_inputs(&mut self, reader: &mut Reader, from: SocketAddr) -> Option<()> {
+        let peer = self.peers.iter().position(|p| p.address == from)?;
+        let players = self.setup.as_ref()?.players;
+        if reader.u8()? as usize != players {
+            return None;
+        }
+        let mut known = vec![];
+        for _ in 0..players {
+            known.push(reader.u32()? as usize);
+        }
+        let horizon = self.complete() + Self::MAX_AHEAD;
+        for _ in 0..reader.u8()? {
+            let slot = reader.u8()? as usize;
+            let first = reader.u32()? as usize;
+            let count = reader.u8()? as usize;
+            // Clients only speak for themselves
+            let allowed = slot < players && (!self.host || slot == self.peers[peer].slot);
+            if !allowed || first + count > horizon {
+                return None;
+            }
+            for tick in first..first + count {
+                let input = reader.input()?;
+                if let Some(inputs) = self.inputs_at(tick, players) {
+                    if inputs[slot].is_none() {
+                        inputs[slot] = Some(input);
+                    }
+                }
+            }
+        }
+        let (simulated, checksum) = (reader.u32()? as usize, reader.u64()?);
+        self.peers[peer].last_heard = Instant::now();
+        self.peers[peer].known = known;
+        // Packets come out of order
+        self.peers[peer].simulated = self.peers[peer].simulated.max(simulated);
+        self.update_known();
+        self.check_remote(simulated, checksum);
+        Some(())
+    }
+
+    fn check_remote(&mut self, simulated: usize, checksum: u64) {
+        if simulated == 0 {
+            return;
+        }
+        if simulated <= self.confirmed {
+            self.compare_checksum(simulated, checksum);
+        } else if !self.remote_checksums.contains(&(simulated, checksum)) {
+            self.remote_checksums.push((simulated, checksum));
+        }
+    }
+
+    // Old ones were compared already, when they came in order
+    fn compare_checksum(&mut self, simulated: usize, checksum: u64) {
+        let ours = (simulated + self.checksums.len())
+            .checked_sub(self.confirmed + 1)
+            .and_then(|i| self.checksums.get(i));
+        if self.desync.is_none() && ours.map_or(false, |&ours| ours != checksum) {
+            self.desync = Some(simulated - 1);
+        }
+    }
+
+    // Once every player confirmed a tick, nobody asks for its inputs or
+    // checksum again. The host also keeps what spectators may still need.
+    fn forget(&mut self) {
+        let mut forget = if self.spectator {
+            self.confirmed
+        } else {
+            self.peers.iter().map(|p| p.simulated).fold(self.confirmed, usize::min)
+        };
+        if self.host {
+            forget = forget.min(self.broadcast_tick);
+            if let Some((tick, _)) = self.broadcast_state {
+                forget = forget.min(tick);
+            }
+            for spectator in &self.spectators {
+                forget = forget.min(spectator.known.unwrap_or(self.broadcast_tick));
+            }
+        }
+        while self.forgotten < forget.min(self.tick) && !self.inputs.is_empty() {
+            self.inputs.pop_front();
+            self.forgotten += 1;
+        }
+        // The last one goes out with every packet
+        while self.checksums.len() > 1 && self.confirmed - self.checksums.len() + 1 < forget {
+            self.checksums.pop_front();
+        }
+    }
+
+    fn update_known(&mut self) {
+        for slot in 0..self.known.len() {
+            while self.input(self.known[slot], slot).is_some() {
+                self.known[slot] += 1;
+            }
+        }
+        let complete = self.complete();
+        if self.host && self.completed.back().map_or(true, |&(tick, _)| tick < complete) {
+            self.completed.push_back((complete, Instant::now()));
+        }
+    }
+}
+
+// Polled every frame, never waits
+fn udp_socket(port: u16) -> io::Result<Box<Socket>> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    socket.set_nonblocking(true)?;
+    Ok(Box::new(socket))
+}
+
+fn write_setup(packet: &mut Vec<u8>, setup: &Setup, slot: usize) {
+    packet.push(slot as u8);
+    packet.extend_from_slice(&u64_to_bytes(setup.seed));
+    packet.push(setup.players as u8);
+    packet.extend_from_slice(&u32_to_bytes(setup.rules.score_limit));
+    packet.extend_from_slice(&u32_to_bytes(setup.rules.time_limit));
+    packet.extend_from_slice(&u32_to_bytes(setup.rules.rounds));
+    packet.push(setup.rollback as u8);
+    packet.extend_from_slice(&u32_to_bytes(setup.level.len() as u32));
+    packet.extend_from_slice(setup.level.as_bytes());
+}
+
+fn read_setup(reader: &mut Reader) -> Option<Setup> {
+    let slot = reader.u8()? as usize;
+    let seed = reader.u64()?;
+    let players = reader.u8()? as usize;
+    let rules = Rules {
+        score_limit: reader.u32()?,
+        time_limit: reader.u32()?,
+        rounds: reader.u32()?,
+    };
+    let rollback = reader.u8()? != 0;
+    let length = reader.u32()? as usize;
+    let level = String::from_utf8(reader.bytes(length)?.to_vec()).ok()?;
+    if slot >= players {
+        return None;
+    }
+    Some(Setup {
+        slot: slot,
+        seed: seed,
+        players: players,
+        level: level,
+        rules: rules,
+        rollback: rollback,
+    })
+}
+
+fn header(kind: u8) -> Vec<u8> {
+    let mut packet = MAGIC.to_vec();
+    packet.push(VERSION);
+    packet.push(kind);
+    packet
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+// Reads a packet from the front, None once it runs out
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes: bytes }
+    }
+
+    // The kind of packet, if it is one of ours
+    fn header(&mut self) -> Option<u8> {
+        if self.bytes(4)? != MAGIC || self.u8()? != VERSION {
+            return None;
+        }
+        self.u8()
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| b[0] as u16 | (b[1] as u16) << 8)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| (0..4).fold(0, |n, i| n | (b[i] as u32) << (8 * i)))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| (0..8).fold(0, |n, i| n | (b[i] as u64) << (8 * i)))
+    }
+
+    fn input(&mut self) -> Option<Input> {
+        let b = self.bytes(2)?;
+        Some(Input {
+            x: b[0] as i8,
+            y: b[1] as i8,
+        })
+    }
+}
+
+fn u16_to_bytes(n: u16) -> [u8; 2] {
+    [n as u8, (n >> 8) as u8]
+}
+
+fn u32_to_bytes(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+fn u64_to_bytes(n: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for i in 0..8 {
+        bytes[i] = (n >> (8 * i)) as u8;
+    }
+    bytes
+}
@@ -0,0 +1,145 @@
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use player::Player;
+use rules::Rules;
+use net::Session;
+
+// Command line options
+pub struct Options {
+    pub seed: u64,
+    pub players: usize,
+    pub level: Option<String>,
+    pub rules: Rules,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    // Save state to start the match from
+    pub state: Option<String>,
+    // Port to host a network game on
+    pub host: Option<u16>,
+    // Address of the host to join
+    pub join: Option<String>,
+    // Address of the host to watch
+    pub watch: Option<String>,
+    // Seconds spectators lag behind the players
+    pub broadcast_delay: u32,
+    pub fullscreen: bool,
+    // Only whole multiples of the base size
+    pub integer_scaling: bool,
+    // Hosted games predict and correct instead of waiting
+    pub rollback: bool,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        let mut options = Options {
+            seed: Self::time_seed(),
+            players: Player::MIN_COUNT,
+            level: None,
+            rules: Rules::new(),
+            record: None,
+            replay: None,
+            state: None,
+            host: None,
+            join: None,
+            watch: None,
+            broadcast_delay: 0,
+            fullscreen: false,
+            integer_scaling: false,
+            rollback: false,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--seed" => {
+                    let value = args.next().ok_or("--seed expects a number")?;
+                    options.seed = value
+                        .parse()
+                        .map_err(|_| format!("invalid seed: {}", value))?;
+                }
+                "--players" => {
+                    let value = args.next().ok_or("--players expects a number")?;
+                    options.players = match value.parse() {
+                        Ok(n) if n >= Player::MIN_COUNT && n <= Player::MAX_COUNT => n,
+                        _ => return Err(format!("invalid player count: {}", value)),
+                    };
+                }
+                "--level" => {
+                    options.level = Some(args.next().ok_or("--level expects a name")?);
+                }
+                "--score-limit" => options.rules.score_limit = number(args.next(), "--score-limit")?,
+                "--time-limit" => options.rules.time_limit = number(args.next(), "--time-limit")?,
+                "--rounds" => {
+                    options.rules.rounds = match number(args.next(), "--rounds")? {
+                        0 => return Err("--rounds expects at least 1".to_string()),
+                        n => n,
+                    };
+                }
+                "--record" => {
+                    options.record = Some(args.next().ok_or("--record expects a file")?);
+                }
+                "--replay" => {
+                    options.replay = Some(args.next().ok_or("--replay expects a file")?);
+                }
+                "--state" => {
+                    options.state = Some(args.next().ok_or("--state expects a file")?);
+                }
+                "--host" => {
+                    let value = args.next().ok_or("--host expects a port")?;
+                    options.host = Some(value.parse().map_err(|_| format!("invalid port: {}", value))?);
+                }
+                "--join" => options.join = Some(address(args.next(), "--join")?),
+                "--watch" => options.watch = Some(address(args.next(), "--watch")?),
+                "--broadcast-delay" => options.broadcast_delay = number(args.next(), "--broadcast-delay")?,
+                "--fullscreen" => options.fullscreen = true,
+                "--integer-scaling" => options.integer_scaling = true,
+                "--rollback" => options.rollback = true,
+                _ => return Err(format!("unknown option: {}", arg)),
+            }
+        }
+        let roles = [options.host.is_some(), options.join.is_some(), options.watch.is_some()];
+        let networked = roles.iter().any(|&r| r);
+        if roles.iter().filter(|&&r| r).count() > 1 {
+            return Err("--host, --join and --watch cannot be used together".to_string());
+        }
+        if options.broadcast_delay > 0 && options.host.is_none() {
+            return Err("--broadcast-delay is for the host".to_string());
+        }
+        if options.rollback && options.host.is_none() {
+            return Err("--rollback is for the host, clients follow it".to_string());
+        }
+        if networked && options.replay.is_some() {
+            return Err("a replay cannot be watched over the network".to_string());
+        }
+        // Replays start from the beginning, spectators come in the middle
+        if options.watch.is_some() && options.record.is_some() {
+            return Err("--watch cannot be used with --record".to_string());
+        }
+        // Replays and network games start from the seed alone
+        if options.state.is_some() && (networked || options.replay.is_some() || options.record.is_some()) {
+            return Err("--state cannot be used with a replay or a network game".to_string());
+        }
+        Ok(options)
+    }
+
+    fn time_seed() -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() ^ (d.subsec_nanos() as u64) << 32,
+            Err(_) => 0,
+        }
+    }
+}
+
+// The port can be left out
+fn address(value: Option<String>, option: &str) -> Result<String, String> {
+    let value = value.ok_or(format!("{} expects an address", option))?;
+    Ok(if value.contains(':') {
+        value
+    } else {
+        format!("{}:{}", value, Session::DEFAULT_PORT)
+    })
+}
+
+fn number(value: Option<String>, option: &str) -> Result<u32, String> {
+    let value = value.ok_or(format!("{} expects a number", option))?;
+    value.parse().map_err(|_| format!("invalid {} value: {}", option, value))
+}
@@ -1,7 +1,267 @@
+use std::io::Read;
+use std::f64::consts::PI;
 use cgmath::Vector2;
+use ggez::graphics;
+use ggez::{Context, GameError, GameResult};
+use toml;
+use rand::Rng;
+use rng::GameRng;
+use helpers;
+use assets::Assets;
+use display::Screen;
+use level::Level;
 
-pub struct Blood {
+// A burst of particles, loaded from resources/particles.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParticleDefinition {
+    pub name: String,
+    pub sprite: String,
+    // Particles per burst
+    pub count: u32,
+    // In degrees, 0 is right and 90 is up
+    #[serde(default = "up")]
+    pub direction: f64,
+    // Degrees around the direction, 360 for every way
+    #[serde(default = "full_circle")]
+    pub spread: f64,
+    // [min, max] in screen heights per second
+    pub speed: [f64; 2],
+    // Seconds before a particle disappears, randomized down to half of it
+    pub lifetime: f64,
+    // Screen heights per second squared, pulling down
+    #[serde(default)]
+    pub gravity: f64,
+    // Becomes transparent as it gets older
+    #[serde(default)]
+    pub fade: bool,
+    #[serde(default = "white")]
+    pub color: [u8; 3],
+    pub size: f64,
+    // Points where it flies instead of spinning
+    #[serde(default)]
+    pub align: bool,
+    // Degrees per second
+    #[serde(default)]
+    pub spin: f64,
+    // Leaves a stain where it hits the ground
+    #[serde(default)]
+    pub decal: bool,
+}
+
+#[derive(Deserialize)]
+struct ParticleFile {
+    emitter: Vec<ParticleDefinition>,
+}
+
+fn up() -> f64 {
+    90.
+}
+
+fn full_circle() -> f64 {
+    360.
+}
+
+fn white() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+impl ParticleDefinition {
+    const FILE: &'static str = "/particles.toml";
+    // Fired by the simulation, they have to be defined
+    pub const REQUIRED: [&'static str; 5] = ["blood", "jump", "landing", "bonus", "factory"];
+
+    pub fn load_all(ctx: &mut Context) -> GameResult<Vec<ParticleDefinition>> {
+        let mut content = String::new();
+        ctx.filesystem.open(Self::FILE)?.read_to_string(&mut content)?;
+        Self::parse(&content).map_err(|e| GameError::ResourceLoadError(format!("{}: {}", Self::FILE, e)))
+    }
+
+    pub fn parse(content: &str) -> Result<Vec<ParticleDefinition>, String> {
+        let file: ParticleFile = toml::from_str(content).map_err(|e| e.to_string())?;
+        for (i, definition) in file.emitter.iter().enumerate() {
+            definition
+                .validate()
+                .map_err(|e| format!("emitter {} ({}): {}", i + 1, definition.name, e))?;
+            if file.emitter[..i].iter().any(|d| d.name == definition.name) {
+                return Err(format!("emitter {} ({}): name already used", i + 1, definition.name));
+            }
+        }
+        for name in &Self::REQUIRED {
+            if Self::find(&file.emitter, name).is_none() {
+                return Err(format!("no emitter named {}", name));
+            }
+        }
+        Ok(file.emitter)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("name is empty".to_string());
+        }
+        if !self.sprite.starts_with('/') {
+            return Err(format!("sprite {} must start with /", self.sprite));
+        }
+        if self.lifetime <= 0. {
+            return Err(format!("lifetime must be positive, got {}", self.lifetime));
+        }
+        if self.size <= 0. {
+            return Err(format!("size must be positive, got {}", self.size));
+        }
+        if self.speed[0] < 0. || self.speed[0] > self.speed[1] {
+            return Err(format!("speed must be [min, max], got {:?}", self.speed));
+        }
+        if self.spread < 0. || self.spread > 360. {
+            return Err(format!("spread must be between 0 and 360, got {}", self.spread));
+        }
+        Ok(())
+    }
+
+    pub fn find(definitions: &[ParticleDefinition], name: &str) -> Option<usize> {
+        definitions.iter().position(|d| d.name == name)
+    }
+
+    // One burst, tag is the index of this definition
+    pub fn emit(&self, tag: usize, rng: &mut GameRng, position: Vector2<f64>) -> Vec<Particle> {
+        (0..self.count)
+            .map(|_| {
+                let angle = (self.direction + self.spread * (rng.gen::<f64>() - 0.5)) * PI / 180.;
+                let speed = self.speed[0] + (self.speed[1] - self.speed[0]) * rng.gen::<f64>();
+                Particle {
+                    tag: tag,
+                    position: position,
+                    previous_position: position,
+                    velocity: Vector2::new(angle.cos() * speed, angle.sin() * speed),
+                    rotation: 0.,
+                    age: 0.,
+                    lifetime: self.lifetime * (0.5 + 0.5 * rng.gen::<f64>()),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Particle {
+    pub tag: usize,
     pub position: Vector2<f64>,
-    pub size: Vector2<f64>,
+    pub previous_position: Vector2<f64>,
+    // Screen heights per second
     pub velocity: Vector2<f64>,
-}
\ No newline at end of file
+    // Radians
+    pub rotation: f64,
+    pub age: f64,
+    pub lifetime: f64,
+}
+
+impl Particle {
+    pub fn draw(
+        &self,
+        ctx: &mut Context,
+        definition: &ParticleDefinition,
+        screen: &Screen,
+        assets: &Assets,
+        alpha: f64,
+    ) -> GameResult<()> {
+        let image = assets.textures.get(assets.particle_images[self.tag]);
+        let position = helpers::interpolate(self.previous_position, self.position, alpha);
+        let rotation = if definition.align {
+            self.velocity.y.atan2(-self.velocity.x)
+        } else {
+            self.rotation
+        };
+        let opacity = if definition.fade {
+            1. - self.age / self.lifetime
+        } else {
+            1.
+        };
+        let color = definition.color;
+        graphics::set_color(ctx, (color[0], color[1], color[2], (opacity * 255.) as u8).into())?;
+        graphics::draw_ex(
+            ctx,
+            image,
+            graphics::DrawParam {
+                dest: helpers::point_from_position(position, screen),
+                scale: helpers::scale(Vector2::new(definition.size, definition.size), screen, image),
+                rotation: rotation as f32,
+                ..Default::default()
+            },
+        )?;
+        graphics::set_color(ctx, (255, 255, 255).into())?;
+        Ok(())
+    }
+}
+
+// Where a particle hit the ground, flattened and for good
+#[derive(Debug, Clone)]
+pub struct Stain {
+    pub tag: usize,
+    pub position: Vector2<f64>,
+    pub rotation: f64,
+}
+
+impl Stain {
+    pub fn draw(
+        &self,
+        ctx: &mut Context,
+        definition: &ParticleDefinition,
+        opacity: f32,
+        screen: &Screen,
+        assets: &Assets,
+    ) -> GameResult<()> {
+        let image = assets.textures.get(assets.particle_images[self.tag]);
+        let mut scale = helpers::scale(Vector2::new(definition.size, definition.size), screen, image);
+        scale.y /= 2.;
+        let color = definition.color;
+        let mut color: graphics::Color = (color[0], color[1], color[2]).into();
+        color.a = opacity;
+        graphics::set_color(ctx, color)?;
+        graphics::draw_ex(
+            ctx,
+            image,
+            graphics::DrawParam {
+                dest: helpers::point_from_position(self.position, screen),
+                scale: scale,
+                rotation: self.rotation as f32,
+                ..Default::default()
+            },
+        )?;
+        graphics::set_color(ctx, (255, 255, 255).into())?;
+        Ok(())
+    }
+}
+
+// Moves every particle by one tick, old ones and the ones below the screen go
+// away, the ones leaving a decal stop on the first platform they fall on
+pub fn update(
+    particles: &mut Vec<Particle>,
+    definitions: &[ParticleDefinition],
+    level: &Level,
+    seconds: f64,
+) -> Vec<Stain> {
+    let mut stains = vec![];
+    for particle in particles.iter_mut() {
+        let definition = &definitions[particle.tag];
+        particle.previous_position = particle.position;
+        particle.velocity.y -= definition.gravity * seconds;
+        particle.position.x += particle.velocity.x * seconds;
+        particle.position.y += particle.velocity.y * seconds;
+        particle.rotation += definition.spin * PI / 180. * seconds;
+        particle.age += seconds;
+        if !definition.decal || particle.velocity.y > 0. {
+            continue;
+        }
+        let x = particle.position.x;
+        if let Some(top) = level.landing(particle.previous_position.y, particle.position.y, x, x) {
+            stains.push(Stain {
+                tag: particle.tag,
+                position: Vector2::new(x, top),
+                rotation: particle.rotation,
+            });
+            // Gone, it lives on as a decal
+            particle.age = particle.lifetime;
+        }
+    }
+    particles.retain(|p| p.age < p.lifetime && p.position.y > -0.6);
+    stains
+}
@@ -1,32 +1,25 @@
+use std::cmp::Ordering;
 use cgmath::Vector2;
-use controls::Controls;
-use animation::PlayerAnimation;
-use bonus::Mutation;
+use bonus::{Mutation, Stacking};
+use animation::{PlayerAnimation, PlayerAnimationStatus};
+use collision::{Aabb, Body};
+use level::Level;
 use assets::Assets;
 use ggez::{Context, GameResult};
 use helpers;
 use display::Screen;
 use ggez::graphics;
-use timer;
-use std::time::Duration;
 
 // Players
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Facing {
     Left,
     Right,
 }
 
-// Players
-#[derive(Debug)]
-pub enum PlayerType {
-    Player1,
-    Player2,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Player {
-    pub tag: PlayerType,
+    pub index: usize,
     pub position: Vector2<f64>,
     pub previous_position: Vector2<f64>,
     pub facing: Facing,
@@ -35,24 +28,51 @@ pub struct Player {
     pub max_velocity: Vector2<f64>,
     pub velocity: Vector2<f64>,
     pub input_axis: Vector2<f64>,
-    pub controls: Controls,
     pub score: u32,
-    pub animation: PlayerAnimation,
+    pub animation_time: f64,
     pub mutations: Vec<Mutation>,
+    // Jumps done since leaving the ground
+    pub air_jumps: u32,
+    // Jumping in mid-air needs a new press
+    pub jump_held: bool,
+    pub deaths: u32,
+    // Seconds before coming back, dead while positive
+    pub respawn: f64,
+    // Seconds left without being stompable
+    pub invulnerable: f64,
 }
 
 impl Player {
-    pub fn new(controls: Controls, tag: PlayerType, animation: PlayerAnimation) -> Player {
-        let position = match tag {
-            PlayerType::Player1 => Vector2::new(0.25, ::GROUND_Y),
-            PlayerType::Player2 => Vector2::new(-0.25, ::GROUND_Y),
-        };
-        let facing = match tag {
-            PlayerType::Player1 => Facing::Left,
-            PlayerType::Player2 => Facing::Right,
+    pub const MIN_COUNT: usize = 2;
+    pub const MAX_COUNT: usize = 8;
+
+    // Tint of each player slot, the sprite sets alternate between slots
+    const PALETTE: [(u8, u8, u8); 8] = [
+        (255, 255, 255),
+        (150, 235, 235),
+        (255, 170, 170),
+        (170, 200, 255),
+        (170, 255, 170),
+        (255, 230, 140),
+        (230, 170, 255),
+        (170, 170, 170),
+    ];
+
+    // Distance from the position to the bottom of the legs
+    pub const FEET: f64 = 0.07;
+    pub const RESPAWN_DELAY: f64 = 1.5;
+    pub const INVULNERABILITY: f64 = 2.;
+
+    pub fn new(index: usize, count: usize, level: &Level) -> Player {
+        let x = Self::spawn_x(index, count);
+        let position = Vector2::new(x, Self::standing_y(level, x));
+        let facing = if position.x > 0. {
+            Facing::Left
+        } else {
+            Facing::Right
         };
         Player {
-            tag: tag,
+            index: index,
             position: position,
             previous_position: position,
             facing: facing,
@@ -61,52 +81,197 @@ impl Player {
             max_velocity: Vector2::new(0.2, 1.0),
             velocity: Vector2::new(0., 0.),
             input_axis: Vector2::new(0., 0.),
-            controls: controls,
             score: 0,
-            animation: animation,
+            animation_time: 0.,
             mutations: vec![],
+            air_jumps: 0,
+            jump_held: false,
+            deaths: 0,
+            respawn: 0.,
+            invulnerable: 0.,
+        }
+    }
+
+    // On the highest platform at x, or falling from the sky if there is none
+    pub fn standing_y(level: &Level, x: f64) -> f64 {
+        match level.ground_below(x, 0.5) {
+            Some(top) => top + Self::FEET,
+            None => 0.5,
         }
     }
 
-    pub fn draw(&mut self, ctx: &mut Context, screen: &Screen) -> GameResult<()> {
+    // Players start spread along the ground, right to left
+    fn spawn_x(index: usize, count: usize) -> f64 {
+        let half_width = if count <= 2 { 0.25 } else { 0.4 };
+        half_width - 2. * half_width * index as f64 / (count - 1) as f64
+    }
+
+    pub fn color(&self) -> graphics::Color {
+        Self::PALETTE[self.index % Self::PALETTE.len()].into()
+    }
+
+    pub fn draw(&self, ctx: &mut Context, screen: &Screen, assets: &Assets, alpha: f64) -> GameResult<()> {
+        // Blinks while invulnerable
+        if !self.is_alive() || (self.invulnerable * 10.) as u32 % 2 == 1 {
+            return Ok(());
+        }
         let mut size = self.size;
-        let mut position = self.position;
-        for m in &mut self.mutations {
+        let mut position = self.interpolated_position(alpha);
+        for m in &self.mutations {
             size *= m.size_factor;
         }
-        let initial_max_velocity = self.max_velocity;
-        let max_velocity = self.max_velocity_mutated();
         position.y += (size.y - self.size.y) / 1.33; // WHY?!
         let dest = helpers::point_from_position(position, screen);
-        let player_image = helpers::player_image(self);
+        let animation = &assets.player_animations[self.index % assets.player_animations.len()];
+        let player_image = assets.textures.get(helpers::player_image(self, animation));
         let scale = helpers::scale(size, screen, player_image);
         let draw_param = graphics::DrawParam {
             dest: dest,
             scale: scale,
             ..Default::default()
         };
-        if max_velocity.x == 0. {
-            graphics::set_color(ctx, (200, 200, 255).into())?;
-        } else if max_velocity.x > initial_max_velocity.x {
-            graphics::set_color(ctx, (255, 200, 200).into())?;
+        // The latest mutation tints the player color
+        let mut color = self.color();
+        if let Some(m) = self.mutations.iter().rev().find(|m| m.duration > 0.) {
+            let tint: graphics::Color = m.tint.into();
+            color.r *= tint.r;
+            color.g *= tint.g;
+            color.b *= tint.b;
+        }
+        if self.is_ghost() {
+            color.a = 0.4;
         }
+        graphics::set_color(ctx, color)?;
         graphics::draw_ex(ctx, player_image, draw_param)?;
+        graphics::set_color(ctx, (255, 255, 255).into())?;
+
+        // Timer bars of the running mutations, above the head
+        let top = position + Vector2::new(0., size.y / 2. + 0.04);
+        helpers::draw_mutation_timers(ctx, self, top, screen)
+    }
+
+    pub fn interpolated_position(&self, alpha: f64) -> Vector2<f64> {
+        helpers::interpolate(self.previous_position, self.position, alpha)
+    }
+
+    pub fn cbox_size_mutated(&self) -> Vector2<f64> {
+        let mut cbox_size = self.cbox_size;
+        for m in &self.mutations {
+            cbox_size *= m.size_factor;
+        }
+        cbox_size
+    }
+
+    pub fn body(&self) -> Body {
+        let cbox_size = self.cbox_size_mutated();
+        // Grown llamas keep their feet on the ground
+        let offset = Vector2::new(0., (cbox_size.y - self.cbox_size.y) / 2.);
+        Body {
+            previous: Aabb::new(self.previous_position + offset, cbox_size),
+            current: Aabb::new(self.position + offset, cbox_size),
+            velocity: self.velocity,
+        }
+    }
+
+    pub fn animation_status(&self) -> PlayerAnimationStatus {
+        if self.velocity.y < 0. {
+            PlayerAnimationStatus::Falling
+        } else if self.velocity.y > 0. {
+            PlayerAnimationStatus::Jumping
+        } else if self.velocity.x != 0. {
+            PlayerAnimationStatus::Walking
+        } else {
+            PlayerAnimationStatus::Standing
+        }
+    }
 
-        if (max_velocity.x == 0.) || (max_velocity.x > initial_max_velocity.x) {
-            graphics::set_color(ctx, (255, 255, 255).into())?;
+    pub fn update_animation(&mut self, seconds: f64) {
+        if self.animation_status() == PlayerAnimationStatus::Walking {
+            self.animation_time += seconds;
+            if self.animation_time > PlayerAnimation::WALK_ANIMATION_CYCLE * 2. {
+                self.animation_time = 0.;
+            }
         }
+    }
 
-        Ok(())
+    pub fn add_mutation(&mut self, mutation: Mutation) {
+        match mutation.stacking {
+            Stacking::Refresh => self.mutations.retain(|m| m.name != mutation.name),
+            Stacking::Stack => {
+                let stacks = self.mutations.iter().filter(|m| m.name == mutation.name).count();
+                if stacks >= mutation.max_stacks as usize {
+                    // The one closest to running out makes room
+                    let oldest = self.mutations
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, m)| m.name == mutation.name)
+                        .min_by(|&(_, a), &(_, b)| a.duration.partial_cmp(&b.duration).unwrap_or(Ordering::Equal))
+                        .map(|(i, _)| i);
+                    if let Some(i) = oldest {
+                        self.mutations.remove(i);
+                    }
+                }
+            }
+            Stacking::Replace => self.mutations.retain(|m| m.group != mutation.group),
+        }
+        self.mutations.push(mutation);
     }
 
-    pub fn update_mutations(&mut self, dt: Duration) {
+    pub fn update_mutations(&mut self, seconds: f64) {
         self.mutations.retain(|m| m.duration > 0.);
         for m in &mut self.mutations {
-            m.duration -= timer::duration_to_f64(dt);
+            m.duration -= seconds;
         }
     }
 
-    fn max_velocity_mutated(&self) -> Vector2<f64> {
+    pub fn is_alive(&self) -> bool {
+        self.respawn <= 0.
+    }
+
+    pub fn die(&mut self) {
+        self.deaths += 1;
+        self.respawn = Self::RESPAWN_DELAY;
+        self.mutations.clear();
+    }
+
+    // feet: one of the level spawn points
+    pub fn respawn_at(&mut self, feet: Vector2<f64>) {
+        self.position = Vector2::new(feet.x, feet.y + Self::FEET);
+        self.previous_position = self.position;
+        self.velocity = Vector2::new(0., 0.);
+        self.respawn = 0.;
+        self.invulnerable = Self::INVULNERABILITY;
+        self.air_jumps = 0;
+    }
+
+    pub fn is_ghost(&self) -> bool {
+        self.mutations.iter().any(|m| m.ghost && m.duration > 0.)
+    }
+
+    pub fn has_shield(&self) -> bool {
+        self.mutations.iter().any(|m| m.shield && m.duration > 0.)
+    }
+
+    // Returns false when there was no shield to break
+    pub fn break_shield(&mut self) -> bool {
+        match self.mutations.iter_mut().find(|m| m.shield && m.duration > 0.) {
+            Some(m) => {
+                m.duration = 0.;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn extra_jumps(&self) -> u32 {
+        self.mutations
+            .iter()
+            .filter(|m| m.duration > 0.)
+            .map(|m| m.extra_jumps)
+            .sum()
+    }
+
+    pub fn max_velocity_mutated(&self) -> Vector2<f64> {
         let mut max_velocity = self.max_velocity; // Maybe I need to copy
         for m in &self.mutations {
             max_velocity.x *= m.velocity_factor.x;
@@ -115,18 +280,23 @@ impl Player {
         max_velocity
     }
 
-    pub fn update_position(
-        &mut self,
-        screen: &Screen,
-        seconds: f64,
-        assets: &Assets,
-    ) -> GameResult<()> {
+    // Returns true when the player just jumped
+    pub fn update_position(&mut self, seconds: f64, level: &Level) -> bool {
         let max_velocity = self.max_velocity_mutated();
+        let mut jumped = false;
+        let cbox_size = self.cbox_size_mutated();
 
         self.velocity.x = seconds * max_velocity.x * self.input_axis.x;
 
-        if helpers::is_on_top(self.position, Vector2::new(0., ::GROUND_Y), screen) {
-            if max_velocity.y == 0. {
+        let (left, right) = self.span(self.position.x, cbox_size);
+        let pressed = self.input_axis.y != 0. && !self.jump_held;
+        self.jump_held = self.input_axis.y != 0.;
+        if !level.is_on_ground(self.feet(), left, right) {
+            if pressed && max_velocity.y > 0. && self.air_jumps < self.extra_jumps() {
+                self.air_jumps += 1;
+                self.velocity.y = seconds * max_velocity.y * self.input_axis.y;
+                jumped = true;
+            } else if max_velocity.y == 0. {
                 if self.velocity.y > 0. {
                     self.velocity.y = 0.;
                 }
@@ -135,22 +305,37 @@ impl Player {
                 self.velocity.y -= seconds * max_velocity.y / ::GRAVITY_MAGIC_NUMBER;
             }
         } else {
+            self.air_jumps = 0;
             self.velocity.y = seconds * max_velocity.y * self.input_axis.y;
-            if self.input_axis.y != 0.0 && max_velocity.y > 0. {
-                match self.tag {
-                    PlayerType::Player1 => assets.jump[0].play()?,
-                    PlayerType::Player2 => assets.jump[1].play()?,
-                };
-            }
+            jumped = self.input_axis.y != 0.0 && max_velocity.y > 0.;
         }
 
         self.previous_position = self.position;
 
+        // Walls
         self.position.x += self.velocity.x;
+        let (left, right) = self.span(self.position.x, cbox_size);
+        if level.blocks(left, right, self.feet(), self.head(cbox_size)) {
+            self.position.x = self.previous_position.x;
+        }
+
+        // Floors and ceilings
+        let (left, right) = self.span(self.position.x, cbox_size);
+        let (previous_feet, previous_head) = (self.feet(), self.head(cbox_size));
         self.position.y += self.velocity.y;
+        if self.velocity.y <= 0. {
+            if let Some(top) = level.landing(previous_feet, self.feet(), left, right) {
+                self.position.y = top + Self::FEET;
+            }
+        } else if let Some(bottom) = level.ceiling(previous_head, self.head(cbox_size), left, right) {
+            self.position.y -= self.head(cbox_size) - bottom;
+            self.velocity.y = 0.;
+        }
 
-        if self.position.y < ::GROUND_Y {
-            self.position.y = ::GROUND_Y;
+        // Falling through a hole comes back from the top
+        if self.position.y < -0.6 {
+            self.position.y = 0.6;
+            self.previous_position.y = self.position.y;
         }
 
         if self.position.x > 0.5 {
@@ -159,6 +344,24 @@ impl Player {
             self.position.x = 0.5;
         }
 
-        Ok(())
+        jumped
+    }
+
+    pub fn is_on_ground(&self, level: &Level) -> bool {
+        let (left, right) = self.span(self.position.x, self.cbox_size_mutated());
+        level.is_on_ground(self.feet(), left, right)
+    }
+
+    pub fn feet(&self) -> f64 {
+        self.position.y - Self::FEET
+    }
+
+    // Grown llamas keep their feet on the ground, see body()
+    fn head(&self, cbox_size: Vector2<f64>) -> f64 {
+        self.position.y + cbox_size.y - self.cbox_size.y / 2.
+    }
+
+    fn span(&self, x: f64, cbox_size: Vector2<f64>) -> (f64, f64) {
+        (x - cbox_size.x / 2., x + cbox_size.x / 2.)
     }
 }
@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use ggez::{GameError, GameResult};
+use input::Input;
+use rules::Rules;
+
+// A recorded match: the seed, the level, the rules and the inputs of every
+// player for every tick.
+//
+// File format, little endian:
+//   "LLRP", version (u8), seed (u64), player count (u8),
+//   score limit (u32), time limit (u32), rounds (u32),
+//   level name length (u32), level name
+//   then runs of identical ticks: tick count (u16), one (x, y) i8 pair per player
+pub struct Replay {
+    pub seed: u64,
+    pub players: usize,
+    pub level: String,
+    pub rules: Rules,
+    pub ticks: Vec<Vec<Input>>,
+}
+
+impl Replay {
+    const MAGIC: &'static [u8; 4] = b"LLRP";
+    const VERSION: u8 = 4;
+
+    pub fn new(seed: u64, players: usize, level: &str, rules: Rules) -> Replay {
+        Replay {
+            seed: seed,
+            players: players,
+            level: level.to_string(),
+            rules: rules,
+            ticks: vec![],
+        }
+    }
+
+    pub fn record(&mut self, inputs: &[Input]) {
+        self.ticks.push(inputs.to_vec());
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> GameResult<()> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.push(Self::VERSION);
+        bytes.extend_from_slice(&u64_to_bytes(self.seed));
+        bytes.push(self.players as u8);
+        bytes.extend_from_slice(&u32_to_bytes(self.rules.score_limit));
+        bytes.extend_from_slice(&u32_to_bytes(self.rules.time_limit));
+        bytes.extend_from_slice(&u32_to_bytes(self.rules.rounds));
+        bytes.extend_from_slice(&u32_to_bytes(self.level.len() as u32));
+        bytes.extend_from_slice(self.level.as_bytes());
+        let mut i = 0;
+        while i < self.ticks.len() {
+            let mut run = 1;
+            while i + run < self.ticks.len() && run < u16::max_value() as usize
+                && self.ticks[i + run] == self.ticks[i]
+            {
+                run += 1;
+            }
+            bytes.extend_from_slice(&u16_to_bytes(run as u16));
+            for input in &self.ticks[i] {
+                bytes.push(input.x as u8);
+                bytes.push(input.y as u8);
+            }
+            i += run;
+        }
+        let mut file = File::create(path)?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> GameResult<Replay> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.len() < 30 || &bytes[0..4] != Self::MAGIC {
+            return Err(invalid("not a replay file"));
+        }
+        if bytes[4] != Self::VERSION {
+            return Err(invalid(&format!("unsupported replay version {}", bytes[4])));
+        }
+        let rules = Rules {
+            score_limit: u32_from_bytes(&bytes[14..18]),
+            time_limit: u32_from_bytes(&bytes[18..22]),
+            rounds: u32_from_bytes(&bytes[22..26]),
+        };
+        let level_end = 30 + u32_from_bytes(&bytes[26..30]) as usize;
+        if bytes.len() < level_end {
+            return Err(invalid("truncated replay file"));
+        }
+        let level = String::from_utf8(bytes[30..level_end].to_vec())
+            .map_err(|_| invalid("invalid level name"))?;
+        let mut replay = Replay::new(u64_from_bytes(&bytes[5..13]), bytes[13] as usize, &level, rules);
+        let run_size = 2 + replay.players * 2;
+        let mut i = level_end;
+        while i < bytes.len() {
+            if i + run_size > bytes.len() {
+                return Err(invalid("truncated replay file"));
+            }
+            let run = u16_from_bytes(&bytes[i..i + 2]) as usize;
+            let inputs: Vec<Input> = bytes[i + 2..i + run_size]
+                .chunks(2)
+                .map(|c| Input {
+                    x: c[0] as i8,
+                    y: c[1] as i8,
+                })
+                .collect();
+            for _ in 0..run {
+                replay.ticks.push(inputs.clone());
+            }
+            i += run_size;
+        }
+        Ok(replay)
+    }
+}
+
+// Plays a replay back, with pause, fast-forward and frame stepping
+pub struct Playback {
+    pub replay: Replay,
+    pub tick: usize,
+    pub paused: bool,
+    pub fast_forward: bool,
+    step: bool,
+}
+
+impl Playback {
+    const FAST_FORWARD_SPEED: usize = 4;
+
+    pub fn new(replay: Replay) -> Playback {
+        Playback {
+            replay: replay,
+            tick: 0,
+            paused: false,
+            fast_forward: false,
+            step: false,
+        }
+    }
+
+    // Watch again from the start
+    pub fn restart(&mut self) {
+        self.tick = 0;
+        self.paused = false;
+        self.step = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn toggle_fast_forward(&mut self) {
+        self.fast_forward = !self.fast_forward;
+    }
+
+    // Advance a single tick while paused
+    pub fn step(&mut self) {
+        self.paused = true;
+        self.step = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tick >= self.replay.ticks.len()
+    }
+
+    // How many simulation ticks to run for one tick of real time
+    pub fn ticks_to_run(&mut self) -> usize {
+        if self.paused {
+            let step = self.step;
+            self.step = false;
+            if step { 1 } else { 0 }
+        } else if self.fast_forward {
+            Self::FAST_FORWARD_SPEED
+        } else {
+            1
+        }
+    }
+
+    pub fn next_inputs(&mut self) -> Option<Vec<Input>> {
+        let inputs = self.replay.ticks.get(self.tick).cloned();
+        if inputs.is_some() {
+            self.tick += 1;
+        }
+        inputs
+    }
+}
+
+fn invalid(message: &str) -> GameError {
+    GameError::ResourceLoadError(message.to_string())
+}
+
+fn u16_to_bytes(n: u16) -> [u8; 2] {
+    [n as u8, (n >> 8) as u8]
+}
+
+fn u16_from_bytes(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn u32_to_bytes(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+fn u32_from_bytes(bytes: &[u8]) -> u32 {
+    let mut n = 0;
+    for i in 0..4 {
+        n |= (bytes[i] as u32) << (i * 8);
+    }
+    n
+}
+
+fn u64_to_bytes(n: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for i in 0..8 {
+        bytes[i] = (n >> (i * 8)) as u8;
+    }
+    bytes
+}
+
+fn u64_from_bytes(bytes: &[u8]) -> u64 {
+    let mut n = 0;
+    for i in 0..8 {
+        n |= (bytes[i] as u64) << (i * 8);
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn save_and_load_give_the_same_replay() {
+        let rules = Rules {
+            score_limit: 70_000,
+            time_limit: 100_000,
+            rounds: 301,
+        };
+        let level = "a level with a name longer than anyone would give it ".repeat(6);
+        let mut replay = Replay::new(0xDEAD_BEEF_0BAD_CAFE, 3, &level, rules);
+        let still = vec![Input::default(); 3];
+        // Longer than a run can count
+        for _ in 0..70_000 {
+            replay.record(&still);
+        }
+        for i in 0..100 {
+            replay.record(&[Input { x: 1, y: 0 }, Input { x: -1, y: 1 }, Input { x: i % 3 - 1, y: 0 }]);
+        }
+        let path = env::temp_dir().join("llamassacre-round-trip.replay");
+        replay.save(&path).unwrap();
+        let loaded = Replay::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.seed, replay.seed);
+        assert_eq!(loaded.players, replay.players);
+        assert_eq!(loaded.level, replay.level);
+        assert_eq!(loaded.rules, replay.rules);
+        assert_eq!(loaded.ticks, replay.ticks);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let path = env::temp_dir().join("llamassacre-not-a.replay");
+        File::create(&path)
+            .and_then(|mut f| f.write_all(b"LLRP this is not a replay at all"))
+            .unwrap();
+        let loaded = Replay::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use rand::Rng;
+
+// Seedable xorshift64* generator, the whole state is a single u64 so a
+// match can be reproduced from its seed
+#[derive(Debug, Clone)]
+pub struct GameRng {
+    state: u64,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        // xorshift gets stuck on zero
+        let state = seed ^ 0x9E37_79B9_7F4A_7C15;
+        GameRng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    // Picks up exactly where another generator was
+    pub fn from_state(state: u64) -> GameRng {
+        GameRng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+}
+
+impl Rng for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
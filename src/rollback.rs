@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use input::Input;
+use net::Session;
+use snapshot::Snapshot;
+use world::{Event, World};
+
+// GGPO style netcode: the game runs on right away, guessing that remote
+// players keep doing what they last did. When a real input arrives and the
+// guess was wrong, the world goes back to its snapshot of that tick and runs
+// again up to the present with what actually happened.
+pub struct Rollback {
+    players: usize,
+    // Known inputs, by tick from the first kept then player
+    inputs: VecDeque<Vec<Option<Input>>>,
+    // Ticks in a row with a known input, for each player
+    known: Vec<usize>,
+    // What each simulated tick from the first kept ran with, guesses included
+    used: VecDeque<Vec<Input>>,
+    // Oldest tick with inputs kept, the ones before can no longer change
+    first: usize,
+    // The world before each tick still liable to change
+    snapshots: VecDeque<(usize, Snapshot)>,
+    // Next tick to simulate
+    pub tick: usize,
+    // Oldest tick simulated with a wrong guess
+    mispredicted: Option<usize>,
+    // Ticks handed out by take_confirmed
+    confirmed: usize,
+    pub rollbacks: u32,
+    pub resimulated: u32,
+    pub deepest: usize,
+}
+
+// A tick that can no longer change
+pub struct Confirmed {
+    pub inputs: Vec<Input>,
+    // Of the world right after it
+    pub checksum: u64,
+    // The match was over before it, nothing happened
+    pub over: bool,
+}
+
+impl Rollback {
+    // How far the game may run on guesses before waiting
+    pub const MAX_PREDICTION: usize = 8;
+
+    pub fn new(players: usize) -> Rollback {
+        Rollback {
+            players: players,
+            inputs: VecDeque::new(),
+            known: vec![0; players],
+            used: VecDeque::new(),
+            first: 0,
+            snapshots: VecDeque::new(),
+            tick: 0,
+            mispredicted: None,
+            confirmed: 0,
+            rollbacks: 0,
+            resimulated: 0,
+            deepest: 0,
+        }
+    }
+
+    // None once forgotten
+    pub fn input(&self, tick: usize, player: usize) -> Option<Input> {
+        if tick < self.first {
+            return None;
+        }
+        self.inputs.get(tick - self.first).and_then(|inputs| inputs[player])
+    }
+
+    pub fn add_input(&mut self, tick: usize, player: usize, input: Input) {
+        // Forgotten ones were known already
+        if tick < self.first || self.input(tick, player).is_some() {
+            return;
+        }
+        while self.first + self.inputs.len() <= tick {
+            self.inputs.push_back(vec![None; self.players]);
+        }
+        self.inputs[tick - self.first][player] = Some(input);
+        if tick < self.tick && self.used[tick - self.first][player] != input {
+            self.mispredicted = Some(self.mispredicted.map_or(tick, |t| t.min(tick)));
+        }
+        while self.input(self.known[player], player).is_some() {
+            self.known[player] += 1;
+        }
+    }
+
+    // Everything the session learnt since last time
+    pub fn pull(&mut self, net: &Session) {
+        for player in 0..self.players {
+            for tick in self.known[player]..net.known(player) {
+                if let Some(input) = net.input(tick, player) {
+                    self.add_input(tick, player, input);
+                }
+            }
+        }
+    }
+
+    // Ticks with the inputs of every player
+    fn complete(&self) -> usize {
+        self.known.iter().cloned().min().unwrap_or(0)
+    }
+
+    // Every tick simulated so far is final
+    pub fn is_settled(&self) -> bool {
+        self.mispredicted.is_none() && self.complete() >= self.tick
+    }
+
+    pub fn can_advance(&self) -> bool {
+        self.tick < self.complete() + Self::MAX_PREDICTION
+    }
+
+    // Corrects past guesses if needed, then runs the next tick
+    pub fn advance(&mut self, world: &mut World) -> Vec<Event> {
+        self.correct(world);
+        let tick = self.tick;
+        let events = self.step(world, tick);
+        self.tick += 1;
+        // Older ticks can no longer change, except to hand them out
+        let keep = self.complete().min(self.confirmed);
+        while self.snapshots.front().map_or(false, |&(t, _)| t < keep) {
+            self.snapshots.pop_front();
+        }
+        // Guesses go back to the last known input, one tick before is enough
+        while self.first + 1 < keep {
+            self.inputs.pop_front();
+            self.used.pop_front();
+            self.first += 1;
+        }
+        events
+    }
+
+    // Runs again from the first wrong guess. Events of the ticks run again
+    // are dropped, the sounds they made cannot be taken back.
+    fn correct(&mut self, world: &mut World) {
+        if let Some(from) = self.mispredicted.take() {
+            let snapshot = self.snapshot(from).expect("snapshot of a mispredicted tick").clone();
+            world.restore(&snapshot);
+            for tick in from..self.tick {
+                self.step(world, tick);
+            }
+            self.rollbacks += 1;
+            self.resimulated += (self.tick - from) as u32;
+            self.deepest = self.deepest.max(self.tick - from);
+        }
+    }
+
+    // Ticks that can no longer change
+    pub fn take_confirmed(&mut self, world: &mut World) -> Vec<Confirmed> {
+        self.correct(world);
+        let mut confirmed = vec![];
+        let end = self.complete().min(self.tick);
+        while self.confirmed < end {
+            let tick = self.confirmed;
+            let checksum = if tick + 1 == self.tick {
+                world.checksum()
+            } else {
+                self.snapshot(tick + 1).expect("snapshot of a confirmed tick").checksum()
+            };
+            let over = self.snapshot(tick).expect("snapshot of a confirmed tick").over;
+            confirmed.push(Confirmed {
+                inputs: self.used[tick - self.first].clone(),
+                checksum: checksum,
+                over: over,
+            });
+            self.confirmed += 1;
+        }
+        confirmed
+    }
+
+    // The world after the ticks handed out by take_confirmed, and their count
+    pub fn confirmed_state(&self, world: &World) -> (usize, Snapshot) {
+        match self.snapshot(self.confirmed) {
+            Some(snapshot) if self.confirmed < self.tick => (self.confirmed, snapshot.clone()),
+            _ => (self.tick, world.snapshot()),
+        }
+    }
+
+    fn step(&mut self, world: &mut World, tick: usize) -> Vec<Event> {
+        let inputs: Vec<Input> = (0..self.players).map(|p| self.guess(tick, p)).collect();
+        let snapshot = world.snapshot();
+        match self.snapshots.iter().position(|&(t, _)| t == tick) {
+            Some(i) => self.snapshots[i].1 = snapshot,
+            None => self.snapshots.push_back((tick, snapshot)),
+        }
+        if self.first + self.used.len() <= tick {
+            self.used.push_back(inputs.clone());
+        } else {
+            self.used[tick - self.first] = inputs.clone();
+        }
+        world.step(&inputs)
+    }
+
+    // The real input if known, otherwise the last one known
+    fn guess(&self, tick: usize, player: usize) -> Input {
+        (self.first..tick + 1)
+            .rev()
+            .filter_map(|t| self.input(t, player))
+            .next()
+            .unwrap_or_default()
+    }
+
+    fn snapshot(&self, tick: usize) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|&&(t, _)| t == tick).map(|&(_, ref s)| s)
+    }
+}
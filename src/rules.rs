@@ -0,0 +1,102 @@
+// How a match is won. A round ends when someone reaches the score limit or
+// when the time is up, the match goes to whoever wins most of the rounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rules {
+    // Points needed to win a round, 0 for no limit
+    pub score_limit: u32,
+    // Length of a round in seconds, 0 for no limit
+    pub time_limit: u32,
+    // Best of that many rounds
+    pub rounds: u32,
+}
+
+impl Rules {
+    pub const SCORE_LIMITS: [u32; 4] = [5, 10, 20, 0];
+    pub const TIME_LIMITS: [u32; 4] = [0, 60, 120, 180];
+    pub const ROUNDS: [u32; 3] = [1, 3, 5];
+
+    pub fn new() -> Rules {
+        Rules {
+            score_limit: 10,
+            time_limit: 0,
+            rounds: 1,
+        }
+    }
+
+    pub fn next_score_limit(&mut self) {
+        self.score_limit = next(&Self::SCORE_LIMITS, self.score_limit);
+    }
+
+    pub fn next_time_limit(&mut self) {
+        self.time_limit = next(&Self::TIME_LIMITS, self.time_limit);
+    }
+
+    pub fn next_rounds(&mut self) {
+        self.rounds = next(&Self::ROUNDS, self.rounds);
+    }
+
+    pub fn rounds_to_win(&self) -> u32 {
+        self.rounds / 2 + 1
+    }
+
+    // Seconds left in the round, if it is timed
+    pub fn time_left(&self, ticks: u32, tick: f64) -> Option<f64> {
+        if self.time_limit == 0 {
+            return None;
+        }
+        Some((self.time_limit as f64 - ticks as f64 * tick).max(0.))
+    }
+
+    pub fn describe(&self) -> String {
+        let score = match self.score_limit {
+            0 => "no score limit".to_string(),
+            n => format!("first to {}", n),
+        };
+        let time = match self.time_limit {
+            0 => "no time limit".to_string(),
+            n => format!("{} rounds", clock(n as f64)),
+        };
+        let rounds = match self.rounds {
+            1 => "single round".to_string(),
+            n => format!("best of {}", n),
+        };
+        format!("{} (S), {} (T), {} (B)", score, time, rounds)
+    }
+}
+
+// What a player did during the whole match
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub points: u32,
+    pub kills: u32,
+    pub deaths: u32,
+    pub bonuses: u32,
+    pub rounds_won: u32,
+}
+
+// Minutes and seconds, rounded up so that 0:00 is only shown once time is up
+pub fn clock(seconds: f64) -> String {
+    let seconds = seconds.ceil() as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// The only index holding the best value, None on a tie
+pub fn leader<I: Iterator<Item = u32>>(values: I) -> Option<usize> {
+    let values: Vec<u32> = values.collect();
+    let best = match values.iter().max() {
+        Some(best) => *best,
+        None => return None,
+    };
+    let mut leaders = values.iter().enumerate().filter(|&(_, v)| *v == best);
+    match (leaders.next(), leaders.next()) {
+        (Some((i, _)), None) => Some(i),
+        _ => None,
+    }
+}
+
+fn next(values: &[u32], current: u32) -> u32 {
+    match values.iter().position(|v| *v == current) {
+        Some(i) => values[(i + 1) % values.len()],
+        None => values[0],
+    }
+}
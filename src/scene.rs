@@ -0,0 +1,87 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scene {
+    Intro,
+    Credits,
+    Controls,
+    Game,
+    Pause,
+    Results,
+    // Waiting for the other players of a network game
+    Lobby,
+}
+
+impl Scene {
+    // Drawn on top of the scene below instead of hiding it
+    pub fn is_overlay(&self) -> bool {
+        *self == Scene::Pause
+    }
+}
+
+// The current scene is the last one, going back pops it. Switching between
+// full screen scenes fades from black, overlays show up at once.
+pub struct SceneStack {
+    scenes: Vec<Scene>,
+    fade: f64,
+}
+
+impl SceneStack {
+    pub const FADE_TIME: f64 = 0.3;
+
+    pub fn new(scenes: Vec<Scene>) -> SceneStack {
+        SceneStack {
+            scenes: scenes,
+            fade: 0.,
+        }
+    }
+
+    pub fn top(&self) -> Scene {
+        *self.scenes.last().expect("empty scene stack")
+    }
+
+    pub fn push(&mut self, scene: Scene) {
+        let previous = self.top();
+        self.scenes.push(scene);
+        self.start_fade(previous);
+    }
+
+    // The bottom scene never goes away
+    pub fn pop(&mut self) {
+        if self.scenes.len() > 1 {
+            let previous = self.scenes.pop().unwrap();
+            self.start_fade(previous);
+        }
+    }
+
+    pub fn replace(&mut self, scene: Scene) {
+        let previous = self.scenes.pop().unwrap();
+        self.scenes.push(scene);
+        self.start_fade(previous);
+    }
+
+    pub fn reset(&mut self, scenes: Vec<Scene>) {
+        let previous = self.top();
+        self.scenes = scenes;
+        self.start_fade(previous);
+    }
+
+    // From the last full screen scene up to the top
+    pub fn visible(&self) -> &[Scene] {
+        let start = self.scenes.iter().rposition(|s| !s.is_overlay()).unwrap_or(0);
+        &self.scenes[start..]
+    }
+
+    pub fn update(&mut self, seconds: f64) {
+        self.fade = (self.fade - seconds).max(0.);
+    }
+
+    // Opacity of the black layer drawn over everything
+    pub fn fade_opacity(&self) -> f32 {
+        (self.fade / Self::FADE_TIME) as f32
+    }
+
+    fn start_fade(&mut self, previous: Scene) {
+        if !previous.is_overlay() && !self.top().is_overlay() {
+            self.fade = Self::FADE_TIME;
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use cgmath::Vector2;
+use ggez::{GameError, GameResult};
+use bonus::{Bonus, BonusText, Factory, Mutation, Stacking};
+use particles::Particle;
+use player::{Facing, Player};
+use rng::GameRng;
+use rules::{Rules, Stats};
+
+// Everything a tick can change, to go back in time. The level, the rules and
+// the definitions stay the same during a match.
+//
+// Encoding, little endian: lists are a count (u32) then their items, floats
+// are their bits, strings are a length (u8) then UTF-8, bools are a u8. The
+// particles come last, after everything the gameplay depends on.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub players: Vec<Player>,
+    pub particles: Vec<Particle>,
+    pub bonus_factory: Factory,
+    pub bonuses: Vec<Bonus>,
+    pub bonuses_text: Vec<BonusText>,
+    pub rng: GameRng,
+    // Only for the particles, which are only for show
+    pub particle_rng: GameRng,
+    pub stats: Vec<Stats>,
+    pub round: u32,
+    pub round_ticks: u32,
+    pub over: bool,
+}
+
+impl Snapshot {
+    // Fingerprint of the gameplay, two machines fed the same inputs have to
+    // agree on it tick after tick. Particles are left out, their maths may
+    // differ a little between systems without the game doing so.
+    pub fn checksum(&self) -> u64 {
+        let mut w = Writer::new();
+        self.write_gameplay(&mut w);
+        // FNV-1a
+        w.bytes
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100_0000_01b3))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        self.write_gameplay(&mut w);
+        w.u64(self.particle_rng.state());
+        w.u32(self.particles.len() as u32);
+        for particle in &self.particles {
+            w.u32(particle.tag as u32);
+            w.vector(particle.position);
+            w.vector(particle.previous_position);
+            w.vector(particle.velocity);
+            w.f64(particle.rotation);
+            w.f64(particle.age);
+            w.f64(particle.lifetime);
+        }
+        w.bytes
+    }
+
+    fn write_gameplay(&self, w: &mut Writer) {
+        w.u32(self.players.len() as u32);
+        for player in &self.players {
+            w.u8(player.index as u8);
+            w.vector(player.position);
+            w.vector(player.previous_position);
+            w.u8(match player.facing {
+                Facing::Left => 0,
+                Facing::Right => 1,
+            });
+            w.vector(player.size);
+            w.vector(player.cbox_size);
+            w.vector(player.max_velocity);
+            w.vector(player.velocity);
+            w.vector(player.input_axis);
+            w.u32(player.score);
+            w.f64(player.animation_time);
+            w.u32(player.mutations.len() as u32);
+            for mutation in &player.mutations {
+                w.string(&mutation.name);
+                w.string(&mutation.group);
+                w.u8(match mutation.stacking {
+                    Stacking::Refresh => 0,
+                    Stacking::Stack => 1,
+                    Stacking::Replace => 2,
+                });
+                w.u32(mutation.max_stacks);
+                w.f64(mutation.duration);
+                w.f64(mutation.total);
+                w.u8(mutation.tint.0);
+                w.u8(mutation.tint.1);
+                w.u8(mutation.tint.2);
+                w.f64(mutation.size_factor);
+                w.vector(mutation.velocity_factor);
+                w.bool(mutation.shield);
+                w.u32(mutation.extra_jumps);
+                w.bool(mutation.ghost);
+            }
+            w.u32(player.air_jumps);
+            w.bool(player.jump_held);
+            w.u32(player.deaths);
+            w.f64(player.respawn);
+            w.f64(player.invulnerable);
+        }
+        let factory = &self.bonus_factory;
+        w.f64(factory.cooldown);
+        w.f64(factory.alt_image_cooldown);
+        w.vector(factory.position);
+        w.vector(factory.previous_position);
+        w.vector(factory.velocity);
+        w.vector(factory.size);
+        w.f32(factory.rotation);
+        w.f32(factory.rotation_velocity);
+        w.u32(self.bonuses.len() as u32);
+        for bonus in &self.bonuses {
+            w.u32(bonus.tag as u32);
+            w.vector(bonus.position);
+            w.vector(bonus.previous_position);
+            w.vector(bonus.size);
+            w.vector(bonus.cbox_size);
+            w.vector(bonus.velocity);
+            w.f32(bonus.rotation);
+            w.bool(bonus.has_collision);
+        }
+        w.u32(self.bonuses_text.len() as u32);
+        for text in &self.bonuses_text {
+            w.u32(text.tag as u32);
+            w.vector(text.position);
+            w.f64(text.cooldown);
+        }
+        w.u64(self.rng.state());
+        w.u32(self.stats.len() as u32);
+        for stats in &self.stats {
+            w.u32(stats.points);
+            w.u32(stats.kills);
+            w.u32(stats.deaths);
+            w.u32(stats.bonuses);
+            w.u32(stats.rounds_won);
+        }
+        w.u32(self.round);
+        w.u32(self.round_ticks);
+        w.bool(self.over);
+    }
+
+    // None if the bytes are not a whole snapshot
+    pub fn decode(bytes: &[u8]) -> Option<Snapshot> {
+        let mut r = Reader::new(bytes);
+        let snapshot = Self::read(&mut r)?;
+        if r.bytes.is_empty() { Some(snapshot) } else { None }
+    }
+
+    fn read(r: &mut Reader) -> Option<Snapshot> {
+        let mut players = vec![];
+        for _ in 0..r.u32()? {
+            let index = r.u8()? as usize;
+            let position = r.vector()?;
+            let previous_position = r.vector()?;
+            let facing = match r.u8()? {
+                0 => Facing::Left,
+                1 => Facing::Right,
+                _ => return None,
+            };
+            let size = r.vector()?;
+            let cbox_size = r.vector()?;
+            let max_velocity = r.vector()?;
+            let velocity = r.vector()?;
+            let input_axis = r.vector()?;
+            let score = r.u32()?;
+            let animation_time = r.f64()?;
+            let mut mutations = vec![];
+            for _ in 0..r.u32()? {
+                mutations.push(Mutation {
+                    name: r.string()?,
+                    group: r.string()?,
+                    stacking: match r.u8()? {
+                        0 => Stacking::Refresh,
+                        1 => Stacking::Stack,
+                        2 => Stacking::Replace,
+                        _ => return None,
+                    },
+                    max_stacks: r.u32()?,
+                    duration: r.f64()?,
+                    total: r.f64()?,
+                    tint: (r.u8()?, r.u8()?, r.u8()?),
+                    size_factor: r.f64()?,
+                    velocity_factor: r.vector()?,
+                    shield: r.bool()?,
+                    extra_jumps: r.u32()?,
+                    ghost: r.bool()?,
+                });
+            }
+            players.push(Player {
+                index: index,
+                position: position,
+                previous_position: previous_position,
+                facing: facing,
+                size: size,
+                cbox_size: cbox_size,
+                max_velocity: max_velocity,
+                velocity: velocity,
+                input_axis: input_axis,
+                score: score,
+                animation_time: animation_time,
+                mutations: mutations,
+                air_jumps: r.u32()?,
+                jump_held: r.bool()?,
+                deaths: r.u32()?,
+                respawn: r.f64()?,
+                invulnerable: r.f64()?,
+            });
+        }
+        let bonus_factory = Factory {
+            cooldown: r.f64()?,
+            alt_image_cooldown: r.f64()?,
+            position: r.vector()?,
+            previous_position: r.vector()?,
+            velocity: r.vector()?,
+            size: r.vector()?,
+            rotation: r.f32()?,
+            rotation_velocity: r.f32()?,
+        };
+        let mut bonuses = vec![];
+        for _ in 0..r.u32()? {
+            bonuses.push(Bonus {
+                tag: r.u32()? as usize,
+                position: r.vector()?,
+                previous_position: r.vector()?,
+                size: r.vector()?,
+                cbox_size: r.vector()?,
+                velocity: r.vector()?,
+                rotation: r.f32()?,
+                has_collision: r.bool()?,
+            });
+        }
+        let mut bonuses_text = vec![];
+        for _ in 0..r.u32()? {
+            bonuses_text.push(BonusText {
+                tag: r.u32()? as usize,
+                position: r.vector()?,
+                cooldown: r.f64()?,
+            });
+        }
+        let rng = GameRng::from_state(r.u64()?);
+        let mut stats = vec![];
+        for _ in 0..r.u32()? {
+            stats.push(Stats {
+                points: r.u32()?,
+                kills: r.u32()?,
+                deaths: r.u32()?,
+                bonuses: r.u32()?,
+                rounds_won: r.u32()?,
+            });
+        }
+        let (round, round_ticks, over) = (r.u32()?, r.u32()?, r.bool()?);
+        let particle_rng = GameRng::from_state(r.u64()?);
+        let mut particles = vec![];
+        for _ in 0..r.u32()? {
+            particles.push(Particle {
+                tag: r.u32()? as usize,
+                position: r.vector()?,
+                previous_position: r.vector()?,
+                velocity: r.vector()?,
+                rotation: r.f64()?,
+                age: r.f64()?,
+                lifetime: r.f64()?,
+            });
+        }
+        Some(Snapshot {
+            players: players,
+            particles: particles,
+            bonus_factory: bonus_factory,
+            bonuses: bonuses,
+            bonuses_text: bonuses_text,
+            rng: rng,
+            particle_rng: particle_rng,
+            stats: stats,
+            round: round,
+            round_ticks: round_ticks,
+            over: over,
+        })
+    }
+}
+
+// A snapshot on disk, with the level and the rules to play it in. A bug
+// report can come with one, to load the exact moment things went wrong.
+//
+// File format, little endian:
+//   "LLSS", version (u8), score limit (u32), time limit (u32), rounds (u32),
+//   level name length (u8), level name, then the snapshot
+pub struct SaveState {
+    pub level: String,
+    pub rules: Rules,
+    pub snapshot: Snapshot,
+}
+
+impl SaveState {
+    const MAGIC: &'static [u8; 4] = b"LLSS";
+    const VERSION: u8 = 5;
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> GameResult<()> {
+        let mut w = Writer::new();
+        w.bytes.extend_from_slice(Self::MAGIC);
+        w.u8(Self::VERSION);
+        w.u32(self.rules.score_limit);
+        w.u32(self.rules.time_limit);
+        w.u32(self.rules.rounds);
+        w.string(&self.level);
+        w.bytes.extend(self.snapshot.encode());
+        let mut file = File::create(path)?;
+        file.write_all(&w.bytes)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> GameResult<SaveState> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let mut r = Reader::new(&bytes);
+        if r.bytes(4) != Some(&Self::MAGIC[..]) {
+            return Err(invalid("not a save state file"));
+        }
+        match r.u8() {
+            Some(Self::VERSION) => (),
+            Some(version) => return Err(invalid(&format!("unsupported save state version {}", version))),
+            None => return Err(invalid("truncated save state file")),
+        }
+        let header = (|| Some((r.u32()?, r.u32()?, r.u32()?, r.string()?)))();
+        let (score_limit, time_limit, rounds, level) = header.ok_or(invalid("truncated save state file"))?;
+        let snapshot = Snapshot::decode(r.bytes).ok_or(invalid("corrupt save state file"))?;
+        Ok(SaveState {
+            level: level,
+            rules: Rules {
+                score_limit: score_limit,
+                time_limit: time_limit,
+                rounds: rounds,
+            },
+            snapshot: snapshot,
+        })
+    }
+
+    // Whether the game has what the snapshot refers to
+    pub fn validate(&self, bonus_definitions: usize, particle_definitions: usize) -> GameResult<()> {
+        let snapshot = &self.snapshot;
+        let players = snapshot.players.len();
+        if players < Player::MIN_COUNT || players > Player::MAX_COUNT {
+            return Err(invalid(&format!("save state is for {} players", players)));
+        }
+        if snapshot.stats.len() != players || snapshot.players.iter().enumerate().any(|(i, p)| p.index != i) {
+            return Err(invalid("save state players do not add up"));
+        }
+        let bonus_tags = snapshot.bonuses.iter().map(|b| b.tag).chain(snapshot.bonuses_text.iter().map(|t| t.tag));
+        if bonus_tags.max().map_or(false, |tag| tag >= bonus_definitions) {
+            return Err(invalid("save state uses an unknown bonus"));
+        }
+        if snapshot.particles.iter().any(|p| p.tag >= particle_definitions) {
+            return Err(invalid("save state uses an unknown particle"));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> GameError {
+    GameError::ResourceLoadError(message.to_string())
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer { bytes: vec![] }
+    }
+
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.bytes.push(b as u8);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend((0..4).map(|i| (n >> (8 * i)) as u8));
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.bytes.extend((0..8).map(|i| (n >> (8 * i)) as u8));
+    }
+
+    fn f32(&mut self, n: f32) {
+        self.u32(n.to_bits());
+    }
+
+    fn f64(&mut self, n: f64) {
+        self.u64(n.to_bits());
+    }
+
+    fn vector(&mut self, v: Vector2<f64>) {
+        self.f64(v.x);
+        self.f64(v.y);
+    }
+
+    // Whole, a cut name would not match its definition any more
+    fn string(&mut self, s: &str) {
+        assert!(s.len() <= u32::max_value() as usize, "{} bytes are too long for a string", s.len());
+        self.u32(s.len() as u32);
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+}
+
+// Reads from the front, None once it runs out
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes: bytes }
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| (0..4).fold(0, |n, i| n | (b[i] as u32) << (8 * i)))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| (0..8).fold(0, |n, i| n | (b[i] as u64) << (8 * i)))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        self.u64().map(f64::from_bits)
+    }
+
+    fn vector(&mut self) -> Option<Vector2<f64>> {
+        Some(Vector2::new(self.f64()?, self.f64()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bot::{Bot, Difficulty};
+    use fixtures;
+    use input::Input;
+    use world::World;
+    use super::*;
+
+    // Bots playing a while, so that there are bonuses, mutations and particles
+    fn played(ticks: usize) -> (World, Vec<Bot>) {
+        let mut world = fixtures::world(7, 4);
+        let mut bots: Vec<Bot> = (0..4).map(|_| Bot::new(Difficulty::Hard)).collect();
+        for _ in 0..ticks {
+            let inputs = think(&world, &mut bots);
+            world.step(&inputs);
+        }
+        (world, bots)
+    }
+
+    fn think(world: &World, bots: &mut [Bot]) -> Vec<Input> {
+        bots.iter_mut().enumerate().map(|(i, bot)| bot.think(world, i)).collect()
+    }
+
+    #[test]
+    fn decode_gives_back_what_was_encoded() {
+        let (world, _) = played(1200);
+        let snapshot = world.snapshot();
+        let mutated = snapshot.players.iter().any(|p| !p.mutations.is_empty());
+        assert!(mutated || !snapshot.bonuses.is_empty());
+        let bytes = snapshot.encode();
+        let decoded = Snapshot::decode(&bytes).unwrap();
+        assert_eq!(decoded.encode(), bytes);
+        assert_eq!(decoded.checksum(), snapshot.checksum());
+        let deaths = |s: &Snapshot| s.players.iter().map(|p| p.deaths).collect::<Vec<_>>();
+        assert_eq!(deaths(&decoded), deaths(&snapshot));
+        assert!(Snapshot::decode(&bytes[..bytes.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn long_strings_are_kept_whole() {
+        let name: String = (0..300).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let mut w = Writer::new();
+        w.string(&name);
+        w.string("");
+        let mut r = Reader::new(&w.bytes);
+        assert_eq!(r.string(), Some(name));
+        assert_eq!(r.string(), Some(String::new()));
+        assert_eq!(r.string(), None);
+    }
+
+    #[test]
+    fn a_restored_world_plays_on_the_same() {
+        let (mut world, mut bots) = played(600);
+        let mut restored = fixtures::world(99, 4);
+        restored.restore(&Snapshot::decode(&world.snapshot().encode()).unwrap());
+        for tick in 0..600 {
+            let inputs = think(&world, &mut bots);
+            world.step(&inputs);
+            restored.step(&inputs);
+            assert_eq!(restored.checksum(), world.checksum(), "tick {}", tick);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use ggez::graphics;
+use ggez::{Context, GameResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontKind {
+    Regular,
+    Small,
+}
+
+// Rendered texts, reused as long as the same string is asked for with the
+// same font. Numbers are drawn digit by digit so that a changing score
+// never renders new text.
+pub struct TextCache {
+    fonts: [graphics::Font; 2],
+    texts: [HashMap<String, graphics::Text>; 2],
+    digits: [Vec<graphics::Text>; 2],
+}
+
+impl TextCache {
+    // Past that many texts for a font, the cache starts over
+    const CAPACITY: usize = 256;
+
+    pub fn new(ctx: &mut Context, font: &graphics::Font, font_small: &graphics::Font) -> GameResult<TextCache> {
+        let mut digits = [vec![], vec![]];
+        for (digits, font) in digits.iter_mut().zip([font, font_small].iter()) {
+            for digit in 0..10 {
+                digits.push(graphics::Text::new(ctx, &digit.to_string(), font)?);
+            }
+        }
+        Ok(TextCache {
+            fonts: [font.clone(), font_small.clone()],
+            texts: [HashMap::new(), HashMap::new()],
+            digits: digits,
+        })
+    }
+
+    pub fn get(&mut self, ctx: &mut Context, kind: FontKind, s: &str) -> GameResult<&graphics::Text> {
+        let i = kind as usize;
+        if !self.texts[i].contains_key(s) {
+            if self.texts[i].len() >= Self::CAPACITY {
+                self.texts[i].clear();
+            }
+            // Empty texts can't be rendered
+            let text = graphics::Text::new(ctx, if s.is_empty() { " " } else { s }, &self.fonts[i])?;
+            self.texts[i].insert(s.to_string(), text);
+        }
+        Ok(&self.texts[i][s])
+    }
+
+    // The label and the number, centered on the given point
+    pub fn draw_number(
+        &mut self,
+        ctx: &mut Context,
+        kind: FontKind,
+        label: &str,
+        n: u32,
+        center: graphics::Point,
+        scale: f32,
+    ) -> GameResult<()> {
+        let digits: Vec<usize> = n.to_string().bytes().map(|b| (b - b'0') as usize).collect();
+        let label_width = if label.is_empty() { 0 } else { self.get(ctx, kind, label)?.width() };
+        let i = kind as usize;
+        let width = label_width + digits.iter().map(|d| self.digits[i][*d].width()).sum::<u32>();
+        let mut x = center.x - width as f32 * scale / 2.;
+        if !label.is_empty() {
+            let text = &self.texts[i][label];
+            let w = label_width as f32 * scale;
+            draw_scaled(ctx, text, graphics::Point::new(x + w / 2., center.y), scale)?;
+            x += w;
+        }
+        for d in digits {
+            let text = &self.digits[i][d];
+            let w = text.width() as f32 * scale;
+            draw_scaled(ctx, text, graphics::Point::new(x + w / 2., center.y), scale)?;
+            x += w;
+        }
+        Ok(())
+    }
+}
+
+fn draw_scaled(ctx: &mut Context, text: &graphics::Text, dest: graphics::Point, scale: f32) -> GameResult<()> {
+    let draw_param = graphics::DrawParam {
+        dest: dest,
+        scale: graphics::Point { x: scale, y: scale },
+        ..Default::default()
+    };
+    graphics::draw_ex(ctx, text, draw_param)
+}
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+use ggez::graphics;
+use ggez::{Context, GameResult};
+
+// Cheap handle to an image owned by a TextureCache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Texture(usize);
+
+// Every image is loaded from disk once, whoever asks for it first, and
+// shared through handles afterwards
+pub struct TextureCache {
+    images: Vec<graphics::Image>,
+    paths: HashMap<String, Texture>,
+}
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache {
+            images: vec![],
+            paths: HashMap::new(),
+        }
+    }
+
+    // Pixel art, scaled without smoothing
+    pub fn load(&mut self, ctx: &mut Context, path: &str) -> GameResult<Texture> {
+        if let Some(texture) = self.paths.get(path) {
+            return Ok(*texture);
+        }
+        let mut image = graphics::Image::new(ctx, path)?;
+        image.set_filter(graphics::FilterMode::Nearest);
+        let texture = Texture(self.images.len());
+        self.images.push(image);
+        self.paths.insert(path.to_string(), texture);
+        Ok(texture)
+    }
+
+    pub fn get(&self, texture: Texture) -> &graphics::Image {
+        &self.images[texture.0]
+    }
+}
@@ -1,24 +1,20 @@
-use ggez::graphics;
 use ggez::timer;
-use ggez::{Context, GameResult};
-use assets::Assets;
+use ggez::Context;
 use std::time::Duration;
 
 pub struct Fps {
-    pub text: graphics::Text,
+    pub value: u32,
     pub cooldown: f64,
 }
 
 impl Fps {
     pub const REFRESH_RATE: f64 = 1.0;
 
-    pub fn update(&mut self, ctx: &mut Context, assets: &Assets, dt: Duration) -> GameResult<()> {
+    pub fn update(&mut self, ctx: &mut Context, dt: Duration) {
         self.cooldown -= timer::duration_to_f64(dt);
         if self.cooldown < 0. {
             self.cooldown = Self::REFRESH_RATE;
-            let t = timer::get_fps(ctx);
-            self.text = graphics::Text::new(ctx, &format!("FPS: {}", t as u32), &assets.font_small)?;
+            self.value = timer::get_fps(ctx) as u32;
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,405 @@
+use cgmath::Vector2;
+use player::{Facing, Player};
+use particles::{self, Particle, ParticleDefinition, Stain};
+use bonus::{Bonus, BonusDefinition, BonusText, Factory};
+use helpers;
+use rng::GameRng;
+use snapshot::Snapshot;
+use input::Input;
+use collision;
+use level::Level;
+use rules::{self, Rules, Stats};
+
+// Things that happened during a step, for the renderer and the sound
+pub enum Event {
+    Jump(usize),
+    Kill { killer: usize, victim: usize },
+    BonusTaken(usize),
+    // The victim survived the stomp
+    ShieldBroken(usize),
+    ScoreChanged(usize),
+    // A particle left a mark on the ground
+    Stain(Stain),
+    // None on a draw
+    RoundOver { winner: Option<usize> },
+    MatchOver { winner: Option<usize> },
+}
+
+// Simulation state, no window, GPU or audio device needed
+pub struct World {
+    pub players: Vec<Player>,
+    pub particles: Vec<Particle>,
+    pub bonus_factory: Factory,
+    pub bonuses: Vec<Bonus>,
+    pub bonuses_text: Vec<BonusText>,
+    pub bonus_definitions: Vec<BonusDefinition>,
+    pub particle_definitions: Vec<ParticleDefinition>,
+    pub rng: GameRng,
+    // Particles draw from their own, changing them does not change the game
+    pub particle_rng: GameRng,
+    pub level: Level,
+    pub rules: Rules,
+    pub stats: Vec<Stats>,
+    // Starts at 1
+    pub round: u32,
+    // Ticks since the round started
+    pub round_ticks: u32,
+    pub over: bool,
+}
+
+impl World {
+    // Every gameplay timer advances by this, whatever the frame duration
+    pub const TICK: f64 = 1. / 60.;
+    // Sets the particles apart from the seed of the game
+    const PARTICLE_SEED: u64 = 0x5041_5254_4943_4C45;
+
+    pub fn new(
+        seed: u64,
+        player_count: usize,
+        level: Level,
+        rules: Rules,
+        bonus_definitions: Vec<BonusDefinition>,
+        particle_definitions: Vec<ParticleDefinition>,
+    ) -> World {
+        World {
+            players: (0..player_count).map(|i| Player::new(i, player_count, &level)).collect(),
+            particles: vec![],
+            bonus_factory: Factory::new(),
+            bonuses: vec![],
+            bonuses_text: vec![],
+            bonus_definitions: bonus_definitions,
+            particle_definitions: particle_definitions,
+            rng: GameRng::new(seed),
+            particle_rng: GameRng::new(seed ^ Self::PARTICLE_SEED),
+            level: level,
+            rules: rules,
+            stats: vec![Stats::default(); player_count],
+            round: 1,
+            round_ticks: 0,
+            over: false,
+        }
+    }
+
+    pub fn time_left(&self) -> Option<f64> {
+        self.rules.time_left(self.round_ticks, Self::TICK)
+    }
+
+    pub fn step(&mut self, inputs: &[Input]) -> Vec<Event> {
+        let dt = Self::TICK;
+        let mut events = vec![];
+        if self.over {
+            return events;
+        }
+
+        // Respawns, away from everybody else
+        for i in 0..self.players.len() {
+            if !self.players[i].is_alive() {
+                self.players[i].respawn -= dt;
+                if self.players[i].is_alive() {
+                    let spawn = helpers::farthest_spawn(&self.level, &self.players, i);
+                    self.players[i].respawn_at(spawn);
+                }
+            }
+        }
+
+        // Update players
+        let mut bursts = vec![];
+        for (i, player) in self.players.iter_mut().enumerate() {
+            if !player.is_alive() {
+                continue;
+            }
+            player.invulnerable = (player.invulnerable - dt).max(0.);
+            if let Some(input) = inputs.get(i) {
+                player.input_axis = input.axis();
+                if input.x < 0 {
+                    player.facing = Facing::Left;
+                } else if input.x > 0 {
+                    player.facing = Facing::Right;
+                }
+            }
+            player.update_mutations(dt);
+            let was_on_ground = player.is_on_ground(&self.level);
+            let feet = Vector2::new(player.position.x, player.feet());
+            if player.update_position(dt, &self.level) {
+                events.push(Event::Jump(i));
+                bursts.push(("jump", feet));
+            } else if !was_on_ground && player.is_on_ground(&self.level) {
+                bursts.push(("landing", Vector2::new(player.position.x, player.feet())));
+            }
+            player.update_animation(dt);
+        }
+        for (name, position) in bursts {
+            self.emit(name, position);
+        }
+
+        // Particles
+        let stains = particles::update(&mut self.particles, &self.particle_definitions, &self.level, dt);
+        events.extend(stains.into_iter().map(Event::Stain));
+
+        // Bonus factory
+        self.bonus_factory.update(&mut self.rng, dt);
+
+        // Bonus text
+        {
+            // Move
+            for bonus_text in &mut self.bonuses_text {
+                bonus_text.position.y += dt * 0.1;
+                bonus_text.cooldown -= dt;
+            }
+            // Remove if out of screen
+            self.bonuses_text
+                .retain(|bonus_text| bonus_text.cooldown > 0.);
+        }
+
+        // Bonus
+        {
+            // Spawn
+            if let Some(bonuses) = self.bonus_factory.spawn(&mut self.rng, dt, &self.bonus_definitions) {
+                for bonus in &bonuses {
+                    self.emit("factory", bonus.position);
+                }
+                self.bonuses.extend(bonuses);
+            }
+            // Move
+            for bonus in &mut self.bonuses {
+                bonus.previous_position = bonus.position;
+                bonus.rotation += (dt * 500. * bonus.velocity.x) as f32;
+                bonus.velocity.y -= dt / ::GRAVITY_MAGIC_NUMBER;
+                if bonus.position.x > 0.5 || bonus.position.x < -0.5 {
+                    bonus.position.x *= -1.;
+                }
+                let previous_bottom = bonus.position.y - Bonus::FEET;
+                bonus.position.y += bonus.velocity.y;
+                bonus.position.x += bonus.velocity.x;
+                // Bounce on platforms
+                let half_width = bonus.size.x / 2.;
+                let (left, right) = (bonus.position.x - half_width, bonus.position.x + half_width);
+                let bottom = bonus.position.y - Bonus::FEET;
+                if let Some(top) = self.level.landing(previous_bottom, bottom, left, right) {
+                    bonus.position.y = top + Bonus::FEET;
+                    bonus.velocity.y *= -0.9;
+                    if bonus.velocity.y < 0.01 {
+                        bonus.velocity.x = 0.;
+                    }
+                }
+            }
+            // Remove if fallen through a hole
+            self.bonuses.retain(|bonus| bonus.position.y > -0.6);
+        }
+
+        // Collision
+        for i in 0..self.players.len() {
+            if !self.players[i].is_alive() {
+                continue;
+            }
+            // With other players
+            for j in (i + 1)..self.players.len() {
+                // Stomped by an earlier one, the dead stay where they fell
+                if !self.players[i].is_alive() {
+                    break;
+                }
+                if !self.players[j].is_alive() || self.players[i].is_ghost() || self.players[j].is_ghost() {
+                    continue;
+                }
+                let frag = match collision::stomp(&self.players[i].body(), &self.players[j].body()) {
+                    Some(true) => Some((i, j)),
+                    Some(false) => Some((j, i)),
+                    None => None,
+                };
+                if let Some((killer, victim)) = frag {
+                    if self.players[victim].invulnerable > 0. {
+                        continue;
+                    }
+                    if self.players[victim].break_shield() {
+                        self.players[killer].velocity.y *= -1.0;
+                        events.push(Event::ShieldBroken(victim));
+                        continue;
+                    }
+                    let position = self.players[victim].position;
+                    self.emit("blood", position);
+                    helpers::kill(&mut self.players, killer, victim);
+                    self.stats[killer].kills += 1;
+                    self.stats[victim].deaths += 1;
+                    events.push(Event::Kill {
+                        killer: killer,
+                        victim: victim,
+                    });
+                    events.push(Event::ScoreChanged(killer));
+                }
+            }
+
+            // With bonuses
+            if !self.players[i].is_alive() {
+                continue;
+            }
+            let body = self.players[i].body();
+            let mut taken = vec![];
+            for bonus in &mut self.bonuses {
+                bonus.has_collision = collision::sweep(&body, &bonus.body()).is_some();
+                if bonus.has_collision {
+                    taken.push(bonus.position);
+                    events.push(Event::BonusTaken(i));
+                    self.stats[i].bonuses += 1;
+                    self.bonuses_text.push(BonusText {
+                        tag: bonus.tag,
+                        position: bonus.position,
+                        cooldown: 1.0,
+                    });
+                    let definition = &self.bonus_definitions[bonus.tag];
+                    if let Some(m) = definition.apply(&mut self.players[i]) {
+                        self.players[i].add_mutation(m);
+                    }
+                    if definition.score != 0 {
+                        events.push(Event::ScoreChanged(i));
+                    }
+                    if definition.teleport {
+                        helpers::teleport(&mut self.players, i, &mut self.rng);
+                    }
+                }
+            }
+            self.bonuses.retain(|bonus| !bonus.has_collision);
+            for position in taken {
+                self.emit("bonus", position);
+            }
+        }
+
+        // Rules
+        self.round_ticks += 1;
+        if self.is_round_over() {
+            self.end_round(&mut events);
+        }
+
+        events
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            players: self.players.clone(),
+            particles: self.particles.clone(),
+            bonus_factory: self.bonus_factory.clone(),
+            bonuses: self.bonuses.clone(),
+            bonuses_text: self.bonuses_text.clone(),
+            rng: self.rng.clone(),
+            particle_rng: self.particle_rng.clone(),
+            stats: self.stats.clone(),
+            round: self.round,
+            round_ticks: self.round_ticks,
+            over: self.over,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let snapshot = snapshot.clone();
+        self.players = snapshot.players;
+        self.particles = snapshot.particles;
+        self.bonus_factory = snapshot.bonus_factory;
+        self.bonuses = snapshot.bonuses;
+        self.bonuses_text = snapshot.bonuses_text;
+        self.rng = snapshot.rng;
+        self.particle_rng = snapshot.particle_rng;
+        self.stats = snapshot.stats;
+        self.round = snapshot.round;
+        self.round_ticks = snapshot.round_ticks;
+        self.over = snapshot.over;
+    }
+
+    pub fn checksum(&self) -> u64 {
+        self.snapshot().checksum()
+    }
+
+    // Particles are simulated with the rest but nothing depends on them
+    fn emit(&mut self, name: &str, position: Vector2<f64>) {
+        if let Some(tag) = ParticleDefinition::find(&self.particle_definitions, name) {
+            let burst = self.particle_definitions[tag].emit(tag, &mut self.particle_rng, position);
+            self.particles.extend(burst);
+        }
+    }
+
+    fn is_round_over(&self) -> bool {
+        let best = self.players.iter().map(|p| p.score).max().unwrap_or(0);
+        let score_reached = self.rules.score_limit > 0 && best >= self.rules.score_limit;
+        let time_up = self.time_left().map_or(false, |t| t <= 0.);
+        score_reached || time_up
+    }
+
+    fn end_round(&mut self, events: &mut Vec<Event>) {
+        let winner = rules::leader(self.players.iter().map(|p| p.score));
+        if let Some(winner) = winner {
+            self.stats[winner].rounds_won += 1;
+        }
+        for (stats, player) in self.stats.iter_mut().zip(&self.players) {
+            stats.points += player.score;
+        }
+        events.push(Event::RoundOver { winner: winner });
+
+        let decided = self.stats.iter().any(|s| s.rounds_won >= self.rules.rounds_to_win());
+        if decided || self.round >= self.rules.rounds {
+            self.over = true;
+            events.push(Event::MatchOver {
+                winner: rules::leader(self.stats.iter().map(|s| s.rounds_won)),
+            });
+            return;
+        }
+
+        // Everyone back to their spawn for the next round
+        self.round += 1;
+        self.round_ticks = 0;
+        let count = self.players.len();
+        self.players = (0..count).map(|i| Player::new(i, count, &self.level)).collect();
+        self.bonuses.clear();
+        self.bonuses_text.clear();
+        for i in 0..count {
+            events.push(Event::ScoreChanged(i));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bot::{Bot, Difficulty};
+    use fixtures;
+    use input::Input;
+
+    // Bots playing a while, the inputs and the checksum of every tick
+    fn play(seed: u64, ticks: usize) -> (Vec<Vec<Input>>, Vec<u64>) {
+        let mut world = fixtures::world(seed, 4);
+        let mut bots: Vec<Bot> = (0..4).map(|_| Bot::new(Difficulty::Hard)).collect();
+        let (mut inputs, mut checksums) = (vec![], vec![]);
+        for _ in 0..ticks {
+            let tick: Vec<Input> = bots.iter_mut()
+                .enumerate()
+                .map(|(i, bot)| bot.think(&world, i))
+                .collect();
+            world.step(&tick);
+            inputs.push(tick);
+            checksums.push(world.checksum());
+        }
+        (inputs, checksums)
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_the_same_match() {
+        let (inputs, checksums) = play(42, 1800);
+        let mut world = fixtures::world(42, 4);
+        for (tick, inputs) in inputs.iter().enumerate() {
+            world.step(inputs);
+            assert_eq!(world.checksum(), checksums[tick], "tick {}", tick);
+        }
+        assert_eq!(play(42, 1800).1, checksums);
+    }
+
+    #[test]
+    fn particles_do_not_change_the_match() {
+        let (inputs, checksums) = play(42, 1800);
+        let mut world = fixtures::world(42, 4);
+        for definition in &mut world.particle_definitions {
+            definition.count *= 3;
+            definition.spread /= 2.;
+        }
+        for inputs in &inputs {
+            world.step(inputs);
+        }
+        assert!(!world.particles.is_empty());
+        assert_eq!(world.checksum(), checksums[1799]);
+    }
+}